@@ -5,12 +5,13 @@ extends Node3D
 var default_material = ShaderMaterial.new()
 var shader_standard: Shader = preload("res://shaders/eq_standard.gdshader")
 var shader_add: Shader = preload("res://shaders/eq_additive.gdshader")
+var shader_animated: Shader = preload("res://shaders/eq_animated.gdshader")
 var textures = {}
 var materials = {}
 var actordefs = {}
 
-func get_shader(shader_type_id: int) -> Shader:
-	if shader_type_id in [0x0B, 0x17]:
+func get_shader(material_fragment: S3DMaterial) -> Shader:
+	if material_fragment.blend_mode() == S3DMaterial.BLEND_MODE_ADDITIVE:
 		return shader_add
 	return shader_standard
 
@@ -33,10 +34,13 @@ func load_archive_textures(archive):
 	var duration = Time.get_ticks_msec() - start
 	print("Time to load textures: %dms" % [duration])
 
-func load_wld_materials(wld):
+func load_wld_materials(wld, archive):
 	for material in wld.materials():
 		if material is S3DMaterial:
-			create_material(material)
+			if material.is_animated() and material.visible():
+				create_animated_material(material, archive)
+			else:
+				create_material(material)
 
 func load_zone(zone_name: String):
 
@@ -55,13 +59,21 @@ func load_zone(zone_name: String):
 	var wld: S3DWld = archive.get_main_wld()
 	
 	# Load all the materials and store them in a dictionary
-	load_wld_materials(wld)
+	load_wld_materials(wld, archive)
 	
-	# Instantiate the zone meshes
+	# Instantiate the zone meshes, with the zone's ambient light added to their baked vertex lighting
 	for eqmesh in wld.meshes():
-		var mesh_inst: MeshInstance3D = build_mesh_inst(eqmesh)
+		var vertex_colors = eqmesh.lit_vertex_colors(wld.mesh_ambient_color(eqmesh))
+		var mesh_inst: MeshInstance3D = build_mesh_inst(eqmesh, vertex_colors)
 		call_deferred("add_child", mesh_inst)
 
+	# Place the zone's lights.  Static lights are already baked into the vertex colors of the zone meshes.
+	var lights_wld = archive.get_lights_wld()
+	if lights_wld:
+		for eqlight in lights_wld.lights():
+			if not eqlight.is_static():
+				call_deferred("add_child", eqlight.to_omni_light())
+
 	# Now get the actordef S3D.
 	# This has the definitions of all the placeable objects in the scene for the zone
 	var actordef_archive: EQArchive = loader.load_archive("{0}/{1}_obj.s3d".format([eqdir, zone_name]))
@@ -73,7 +85,7 @@ func load_zone(zone_name: String):
 	var actordef_wld = actordef_archive.get_main_wld()
 	
 	# Load all the materials and store them in a dictionary
-	load_wld_materials(actordef_wld)
+	load_wld_materials(actordef_wld, actordef_archive)
 	
 	# Store references to the actordefs in a dictionary.
 	# Note that this will retain hold of the underlying WLD data.
@@ -96,18 +108,18 @@ func get_texture(texture_filename: String) -> Texture:
 	return textures[texture_filename]
 	
 func get_texture_for_material(material_fragment: S3DMaterial) -> Texture:
-	var texture_filenames = material_fragment.texture_filenames()
-	var num_textures = len(texture_filenames)
-	if num_textures > 1:
-		# Note that AnimatedTexture is deprecated, and a custom shader that handles
-		# bitmap animations would be better.
-		var anim = AnimatedTexture.new()
-		anim.frames = len(texture_filenames)
-		for i in range(num_textures):
-			anim.set_frame_texture(i, get_texture(texture_filenames[i]))
-			anim.set_frame_duration(i, material_fragment.delay())
-		return anim
-	return get_texture(texture_filenames[0])
+	return get_texture(material_fragment.texture_filename())
+
+func create_animated_material(material_fragment: S3DMaterial, archive: EQArchive) -> Material:
+	# Animated materials like water and lava have all of their frames packed into a single Texture2DArray,
+	# and the shader picks the frame from the time.
+	var frames = archive.get_texture_array(material_fragment)
+	var material = ShaderMaterial.new()
+	material.set_name(material_fragment.name())
+	material.shader = shader_animated
+	material_fragment.apply_animation_parameters(material, frames)
+	materials[material_fragment.name()] = material
+	return material
 
 func create_material(material_fragment: S3DMaterial) -> Material:
 	var material_name = material_fragment.name()
@@ -121,10 +133,10 @@ func create_material(material_fragment: S3DMaterial) -> Material:
 	var texture = get_texture_for_material(material_fragment)
 
 	var shader_type_id = material_fragment.shader_type_id()
-	# Note: I am just using standard material here but you'd need to provide different shaders for the various shader types: `material.shader_type_id()`
+	# Note: I am just using two shaders here.  For a ready-made StandardMaterial3D configured from the render method, see `S3DMaterial.to_material()`
 	var material = ShaderMaterial.new()
 	material.set_name(material_name)
-	material.shader = get_shader(shader_type_id)
+	material.shader = get_shader(material_fragment)
 	material.set_shader_parameter("diffuse", texture)
 	material.set_shader_parameter("shader_type_id", shader_type_id)
 	# When the texture was first loaded, a metadata item was stored on it which, for cut-out transparent textures, is the color to be cut out.
@@ -136,58 +148,13 @@ func create_material(material_fragment: S3DMaterial) -> Material:
 	return material
 
 func build_mesh(eqmesh: S3DMesh, vertex_colors: PackedColorArray = []) -> ArrayMesh:
-	var arrays = []
-	arrays.resize(Mesh.ARRAY_MAX)
-	arrays[Mesh.ARRAY_VERTEX] = eqmesh.vertices()
-	arrays[Mesh.ARRAY_NORMAL] = eqmesh.normals()
-	# ActorInstances have their own vertex_colors array.
-	if vertex_colors:
-		arrays[Mesh.ARRAY_COLOR] = vertex_colors
-	else:
-		# For zone meshes, use the vertex_colors that are part of the mesh.
-		vertex_colors = eqmesh.vertex_colors()
-		if vertex_colors:
-			arrays[Mesh.ARRAY_COLOR] = vertex_colors
-	var uvs = eqmesh.uvs()
-	if uvs:
-		arrays[Mesh.ARRAY_TEX_UV] = uvs
-	var bone_indices = eqmesh.bone_indices()
-	if bone_indices:
-		arrays[Mesh.ARRAY_BONES] = bone_indices
-		arrays[Mesh.ARRAY_WEIGHTS] = eqmesh.bone_weights()
-	
-	var mesh = ArrayMesh.new()
+	# ActorInstances have their own vertex_colors array, which overrides the mesh's own vertex colors.
+	# Invisible materials are stored as null in the materials dictionary, so their polygons are skipped.
+	return eqmesh.to_array_mesh(materials, vertex_colors, true)
 	
-	var surf_idx = 0
-
-	for face_material_group in eqmesh.face_material_groups():
-		var material_name = face_material_group[0]
-		var indices = face_material_group[1]
-		if len(indices) < 1:
-			continue
-		var material: Material = null
-		if not material_name in materials:
-			push_error("Missing material: %s" % [material_name])
-			material = default_material
-		else:
-			material = materials[material_name]
-		if material == null:
-			# If material is null, it means it is invisible.
-			# Skip the polygons entirely.
-			continue
 
-		arrays[Mesh.ARRAY_INDEX] = indices
-		
-		mesh.add_surface_from_arrays(Mesh.PRIMITIVE_TRIANGLES, arrays)
-		mesh.surface_set_material(surf_idx, material)
-		
-		surf_idx += 1
-	
-	return mesh
-	
-
-func build_mesh_inst(eqmesh: S3DMesh) -> MeshInstance3D:
-	var mesh = build_mesh(eqmesh)
+func build_mesh_inst(eqmesh: S3DMesh, vertex_colors: PackedColorArray = []) -> MeshInstance3D:
+	var mesh = build_mesh(eqmesh, vertex_colors)
 	var mesh_inst = MeshInstance3D.new()
 	mesh_inst.mesh = mesh
 	mesh_inst.name = eqmesh.name()
@@ -240,7 +207,7 @@ func load_chr(s3d_name) -> Array[Node3D]:
 	var wld: S3DWld = archive.get_main_wld()
 	
 	# Load all the materials and store them in a dictionary
-	load_wld_materials(wld)
+	load_wld_materials(wld, archive)
 
 	# Load all the animations and store them in a dictionary
 
@@ -253,12 +220,19 @@ func load_chr(s3d_name) -> Array[Node3D]:
 		actor_node.position = Vector3(i*20, 0, 0)
 		i += 1
 		
-		var skeleton = build_skeleton(hiersprite)
-		skeleton.name = hiersprite.name()
+		var skeleton = hiersprite.to_skeleton()
 		actor_node.add_child(skeleton)
 		
+		# The mesh vertices are stored relative to their bones, so they are transformed into bind space
+		# to match the rest pose of the skeleton.
+		var bone_transforms = hiersprite.global_rest_transforms()
+		var skin = hiersprite.to_skin()
 		for eqmesh in hiersprite.meshes():
-			var mesh_inst = build_mesh_inst(eqmesh)
+			var mesh_inst = MeshInstance3D.new()
+			mesh_inst.mesh = eqmesh.to_bind_pose_array_mesh(materials, bone_transforms, true)
+			mesh_inst.name = eqmesh.name()
+			mesh_inst.position = eqmesh.center()
+			mesh_inst.skin = skin
 			skeleton.add_child(mesh_inst)
 		
 		var animation_player = build_animation_player(hiersprite)
@@ -286,40 +260,6 @@ func build_animation_player(eqskel: S3DHierSprite) -> AnimationPlayer:
 	
 	return animation_player
 
-func build_skeleton(eqskel: S3DHierSprite) -> Skeleton3D:
-	var skeleton = Skeleton3D.new()
-	
-	
-	# First create all the bones
-	for bone in eqskel.bones():
-		var bone_name = bone.name()
-
-		# Quick fix - in Godot bones cannot have duplicate names
-		for i in skeleton.get_bone_count():
-			if skeleton.get_bone_name(i) == bone_name:
-				bone_name = "%s_2" % [bone_name]
-				break
-
-		skeleton.add_bone(bone_name)
-		
-	# Then setup parenting - because the parents must exist first.
-	# Also set the rest pose
-
-	# Note that it is better to actually set the rest pose (skeleton.set_bone_rest)
-	# However to do this, you will need to pre-deform the character meshes to match
-	# The rest pose, which is beyond the scope of this example.
-
-	var bone_index = 0
-	for bone in eqskel.bones():
-		if bone.parent_index() >= 0:
-			skeleton.set_bone_parent(bone_index, bone.parent_index())
-			skeleton.set_bone_pose_position(bone_index, bone.rest_position())
-			skeleton.set_bone_pose_rotation(bone_index, bone.rest_quaternion())
-		bone_index += 1
-	
-	return skeleton
-		
-
 # If running in Editor mode, this can be called to make the instantiated objects visible in the Editor.
 # Be warned however that they will be saved in the tscn.
 func own_children():
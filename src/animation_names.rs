@@ -0,0 +1,81 @@
+use crate::util::animation::{AnimationName, AnimationNames};
+use godot::classes::RefCounted;
+use godot::prelude::*;
+
+/// The mapping from the raw animation prefixes in track names (`C01`, `D02`, `L01`...) to a category
+/// ("combat", "damage", "locomotion", "passive" or "social"), a readable alias (e.g. "walk") and whether the animation loops.
+///
+/// A new EQAnimationNames holds the default table.  Change entries with `set_entry` and `remove_entry`,
+/// then pass it to `S3DHierSprite.set_animation_names`.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct EQAnimationNames {
+    base: Base<RefCounted>,
+    names: AnimationNames,
+}
+
+#[godot_api]
+impl EQAnimationNames {
+    /// Adds or replaces the entry for a prefix.  An empty alias means the animation only has its raw name.
+    #[func]
+    pub fn set_entry(&mut self, prefix: GString, category: GString, alias: GString, looping: bool) {
+        self.names.set(
+            &prefix.to_string(),
+            AnimationName {
+                category: category.to_string(),
+                alias: alias.to_string(),
+                looping,
+            },
+        );
+    }
+
+    #[func]
+    pub fn remove_entry(&mut self, prefix: GString) {
+        self.names.remove(&prefix.to_string());
+    }
+
+    /// Removes every entry, including the defaults.
+    #[func]
+    pub fn clear(&mut self) {
+        self.names = AnimationNames::empty();
+    }
+
+    /// The category of an animation, from its raw name (e.g. "L01").
+    /// Prefixes without an entry get a category from their first letter.
+    #[func]
+    pub fn category(&self, animation_name: GString) -> GString {
+        GString::from(self.names.category(&animation_name.to_string()))
+    }
+
+    /// The alias of an animation, from its raw name, or an empty string if it has none.
+    #[func]
+    pub fn alias(&self, animation_name: GString) -> GString {
+        GString::from(self.names.alias(&animation_name.to_string()).unwrap_or_default())
+    }
+
+    #[func]
+    pub fn is_looping(&self, animation_name: GString) -> bool {
+        self.names.is_looping(&animation_name.to_string())
+    }
+
+    /// The whole table, where keys are prefixes and values are Dictionaries with the keys `category`, `alias` and `looping`.
+    #[func]
+    pub fn entries(&self) -> Dictionary {
+        self.names
+            .entries()
+            .map(|(prefix, name)| {
+                let mut entry = Dictionary::new();
+                entry.set("category", GString::from(&name.category));
+                entry.set("alias", GString::from(&name.alias));
+                entry.set("looping", name.looping);
+                (GString::from(prefix), entry)
+            })
+            .collect()
+    }
+}
+
+impl EQAnimationNames {
+    pub fn names(&self) -> AnimationNames {
+        self.names.clone()
+    }
+}
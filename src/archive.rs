@@ -1,22 +1,48 @@
 use crate::util::sound::sound_from_bytes;
-use crate::util::texture::{image_from_bmp, tex_from_bmp};
-#[cfg(feature = "dds")]
-use crate::util::texture::image_from_dds;
-use crate::wld::S3DWld;
+use crate::texture_options::EQTextureOptions;
+use crate::util::texture::{apply_image_options, decode_image, image_from_decoded, is_image_filename, tex_array_from_images, tex_from_image, DecodedImage, TextureOptions};
+use crate::fragments::S3DMaterial;
+use crate::wld::{parse_wld, S3DWld};
+use crate::error::{EQLoadError, LoadError, LoadErrorKind};
 use libeq_archive::EqArchive;
-use godot::classes::{AudioStreamWav, ImageTexture, RefCounted, Image};
+use libeq_wld::parser::WldDoc;
+use godot::classes::{AudioStreamWav, ImageTexture, Resource, Image, Texture2DArray};
 use godot::prelude::*;
+use std::collections::HashMap;
 use std::fs::File;
+use std::io::{Cursor, Read, Seek};
 use std::path::Path;
 use std::ffi::OsStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
+/// An S3D archive.  This is a Resource so that it can be returned and cached by `EQResourceFormatLoader`.
 #[derive(GodotClass)]
-#[class(init)]
+#[class(init, base=Resource)]
 pub struct EQArchive {
-    base: Base<RefCounted>,
+    base: Base<Resource>,
     archive: Option<EqArchive>,
     /// The file stem of the archive, e.g. "rivervale".  This is used to get the main WLD out of the archive without specifying its name.
     name: String,
+    /// The error that caused the most recent WLD lookup to return null.
+    last_error: Option<LoadError>,
+    /// The options used by `get_image` and `get_texture`
+    texture_options: TextureOptions,
+    /// Images that were already decoded on a worker thread by `EQArchiveLoader.load_archive_async`.
+    preloaded_images: HashMap<String, DecodedImage>,
+    /// The options the preloaded images were decoded with
+    preloaded_options: TextureOptions,
+    /// WLDs that were already parsed on a worker thread by `EQArchiveLoader.load_archive_async`.
+    preloaded_wlds: HashMap<String, Arc<WldDoc>>,
+}
+
+/// The result of reading an archive and decoding its contents on a worker thread.
+pub struct ParsedArchive {
+    pub archive: EqArchive,
+    pub images: HashMap<String, DecodedImage>,
+    pub options: TextureOptions,
+    pub wlds: HashMap<String, Arc<WldDoc>>,
 }
 
 #[godot_api]
@@ -24,50 +50,81 @@ impl EQArchive {
     /// Returns a list of all filenames within the archive.
     #[func]
     pub fn get_filenames(&mut self) -> PackedStringArray {
-        self.archive
-            .as_ref()
-            .expect("The load() method must be called to initialize this class.")
+        let Some(archive) = self.archive.as_ref() else {
+            godot_error!(
+                "{}",
+                LoadError::new(
+                    LoadErrorKind::InvalidArchive,
+                    "The load() method must be called to initialize this class.",
+                )
+            );
+            return PackedStringArray::new();
+        };
+        archive
             .iter()
             .map(|(s,_)| GString::from(s))
             .collect()
     }
 
-    /// Returns a Texture2D representation of the given bitmap filename
+    /// Returns a Texture2D representation of the given image filename (BMP or DDS)
     #[func]
     pub fn get_texture(&self, filename: GString) -> Option<Gd<ImageTexture>> {
-        let data = self._get(filename.to_string().as_str())?;
-        tex_from_bmp(data)
+        self._get_texture(filename.to_string().as_str(), &self.texture_options)
+    }
+
+    /// Returns a Image representation of the given image filename (BMP or DDS)
+    /// DXT compressed DDS images are kept in their compressed format, with their mipmaps.
+    #[func]
+    pub fn get_image(&self, filename: GString) -> Option<Gd<Image>> {
+        self._get_image(filename.to_string().as_str(), &self.texture_options)
+    }
+
+    /// Like `get_texture`, but decoded with the given options instead of the archive's options.
+    #[func]
+    pub fn get_texture_with_options(
+        &self,
+        filename: GString,
+        options: Gd<EQTextureOptions>,
+    ) -> Option<Gd<ImageTexture>> {
+        self._get_texture(filename.to_string().as_str(), &options.bind().options())
+    }
+
+    /// Like `get_image`, but decoded with the given options instead of the archive's options.
+    #[func]
+    pub fn get_image_with_options(
+        &self,
+        filename: GString,
+        options: Gd<EQTextureOptions>,
+    ) -> Option<Gd<Image>> {
+        self._get_image(filename.to_string().as_str(), &options.bind().options())
+    }
+
+    /// Packs all frames of an animated material into a single Texture2DArray, with one layer per frame.
+    /// The frames are decoded with the archive's texture options.
+    /// Materials with a single texture give an array with one layer, so the same shader can be used for both.
+    ///
+    /// The frame count and the duration of each frame in seconds are stored as the "frame_count" and "frame_duration" metadata.
+    /// `S3DMaterial.apply_animation_parameters` passes them to a shader using the `frames`, `frame_count` and `frame_duration` uniforms.
+    #[func]
+    pub fn get_texture_array(&self, material: Gd<S3DMaterial>) -> Option<Gd<Texture2DArray>> {
+        let material = material.bind();
+        let frames = material
+            .texture_filenames()
+            .as_slice()
+            .iter()
+            .map(|filename| self._get_image(filename.to_string().as_str(), &self.texture_options))
+            .collect::<Option<Vec<_>>>()?;
+        tex_array_from_images(&frames, material.delay())
             .map_err(|e| {
-                godot_error!("Failed to load image from {filename}: {e}");
+                godot_error!("Failed to create texture array for {}: {e}", material.name());
             })
             .ok()
     }
 
-    /// Returns a Image representation of the given bitmap filename
+    /// Sets the options used by `get_image` and `get_texture`.
     #[func]
-    pub fn get_image(&self, filename: GString) -> Option<Gd<Image>> {
-        let data = self._get(filename.to_string().as_str())?;
-        match Path::new(filename.to_string().as_str()).extension().and_then(OsStr::to_str).expect("Filename should have extension") {
-            "bmp" => {
-                image_from_bmp(data)
-                .map_err(|e| {
-                    godot_error!("Failed to load image from {filename}: {e}");
-                })
-                .ok()
-            },
-            #[cfg(feature = "dds")]
-            "dds" => {
-                image_from_dds(data)
-                .map_err(|e| {
-                    godot_error!("Failed to load image from {filename}: {e}");
-                })
-                .ok()
-            }
-            _ => {
-                godot_error!("Unsupported image format: {filename}");
-                None
-            }
-        }        
+    pub fn set_texture_options(&mut self, options: Gd<EQTextureOptions>) {
+        self.texture_options = options.bind().options();
     }
 
     /// Returns a Sound representation of the given audio filename (WAV)
@@ -82,29 +139,37 @@ impl EQArchive {
     }
 
     /// Returns an EQWld object representing a WLD file
+    /// Returns null if the file is missing or cannot be parsed - see `last_error()` for details.
     #[func]
-    pub fn get_wld(&self, filename: GString) -> Option<Gd<S3DWld>> {
-        self._get_wld(filename.to_string().as_str())
+    pub fn get_wld(&mut self, filename: GString) -> Option<Gd<S3DWld>> {
+        self._get_wld_checked(filename.to_string().as_str())
     }
 
     /// Returns the main WLD inside the S3D file.
     /// For Zone S3Ds, this is the WLD containing the zone data.
     /// For ActorDef and Character S3Ds, this is the only WLD in the archive.
     #[func]
-    pub fn get_main_wld(&self) -> Option<Gd<S3DWld>> {
-        self._get_wld(&format!("{0}.wld", &self.name))
+    pub fn get_main_wld(&mut self) -> Option<Gd<S3DWld>> {
+        let filename = format!("{0}.wld", &self.name);
+        self._get_wld_checked(&filename)
     }
 
     /// In Zone S3Ds, this will return the lights.wld within the archive.
     #[func]
-    pub fn get_lights_wld(&self) -> Option<Gd<S3DWld>> {
-        self._get_wld("lights.wld")
+    pub fn get_lights_wld(&mut self) -> Option<Gd<S3DWld>> {
+        self._get_wld_checked("lights.wld")
     }
 
     /// In Zone S3Ds, this will return the objects.wld within the archive.
     #[func]
-    pub fn get_actorinst_wld(&self) -> Option<Gd<S3DWld>> {
-        self._get_wld("objects.wld")
+    pub fn get_actorinst_wld(&mut self) -> Option<Gd<S3DWld>> {
+        self._get_wld_checked("objects.wld")
+    }
+
+    /// Returns the error that caused the most recent WLD lookup to return null, or null if it succeeded.
+    #[func]
+    pub fn last_error(&self) -> Option<Gd<EQLoadError>> {
+        self.last_error.as_ref().map(LoadError::to_gd)
     }
 
     // FIXME: This should return Variant::nil() if the file does't exist.
@@ -122,39 +187,226 @@ impl EQArchive {
 impl EQArchive {
     /// Initializer to be called by factory
     /// Not possible to initialize in GDScript
-    pub fn load(&mut self, filename: &str) {
+    pub fn load(&mut self, filename: &str) -> Result<(), LoadError> {
         godot_print!("Loading archive: {0}", &filename);
-        let file = File::open(&filename)
-            .map_err(|e| godot_error!("Failed to open archive: {filename}: {e}"))
-            .unwrap();
+        let file = File::open(&filename).map_err(|e| {
+            LoadError::new(LoadErrorKind::Io, format!("Failed to open archive: {e}"))
+                .with_filename(filename)
+        })?;
+        self.read(filename, file)
+    }
+
+    /// Initializer for archives that have already been read into memory, e.g. through Godot's FileAccess.
+    /// The filename is only used to name the archive.
+    pub fn load_bytes(&mut self, filename: &str, data: Vec<u8>) -> Result<(), LoadError> {
+        godot_print!("Loading archive: {0}", &filename);
+        self.read(filename, Cursor::new(data))
+    }
+
+    fn read<R: Read + Seek>(&mut self, filename: &str, reader: R) -> Result<(), LoadError> {
+        self.archive = Some(read_archive(filename, reader)?);
+        self.name = archive_name(filename);
+        Ok(())
+    }
+
+    /// Initializer for an archive whose contents were decoded on a worker thread.
+    pub fn load_parsed(&mut self, filename: &str, parsed: ParsedArchive) {
+        self.archive = Some(parsed.archive);
+        self.name = archive_name(filename);
+        self.preloaded_images = parsed.images;
+        self.preloaded_options = parsed.options;
+        self.texture_options = parsed.options;
+        self.preloaded_wlds = parsed.wlds;
+    }
+
+    pub fn set_options(&mut self, options: TextureOptions) {
+        self.texture_options = options;
+    }
 
-        self.archive = Some(
-            EqArchive::read(file)
-                .map_err(|e| godot_error!("Failed to parse S3D archive: {filename}: {e:?}"))
-                .unwrap(),
-        );
-        self.name = String::from(Path::new(&filename).file_stem().unwrap().to_str().unwrap());
+    fn _get_image(&self, filename: &str, options: &TextureOptions) -> Option<Gd<Image>> {
+        let preloaded = self
+            .preloaded_images
+            .get(filename)
+            .filter(|_| options.decodes_like(&self.preloaded_options));
+        let image = match preloaded {
+            Some(decoded) => image_from_decoded(decoded),
+            None => {
+                let data = self._get(filename)?;
+                decode_image(filename, data, options).and_then(|decoded| image_from_decoded(&decoded))
+            }
+        };
+        let mut image = image
+            .map_err(|e| {
+                godot_error!("Failed to load image from {filename}: {e}");
+            })
+            .ok()?;
+        apply_image_options(&mut image, options);
+        Some(image)
     }
+
+    fn _get_texture(&self, filename: &str, options: &TextureOptions) -> Option<Gd<ImageTexture>> {
+        let image = self._get_image(filename, options)?;
+        tex_from_image(image, options)
+            .map_err(|e| {
+                godot_error!("Failed to load image from {filename}: {e}");
+            })
+            .ok()
+    }
+
     /// Attempt to get the given data from the archive.
     /// An error is printed in Godot if the file does not exist.
     fn _get(&self, filename: &str) -> Option<Vec<u8>> {
+        self._try_get(filename)
+            .map_err(|e| godot_error!("{e}"))
+            .ok()
+    }
+
+    /// Attempt to get the given data from the archive, returning an error if the file does not exist.
+    fn _try_get(&self, filename: &str) -> Result<Vec<u8>, LoadError> {
         self.archive
             .as_ref()
-            .expect("The load() method must be called to initialize this class.")
+            .ok_or_else(|| {
+                LoadError::new(
+                    LoadErrorKind::InvalidArchive,
+                    "The load() method must be called to initialize this class.",
+                )
+            })?
             .iter()
             .find(|(name, _)| name == &filename)
-            .or_else(|| { godot_error!("{filename} not found in from archive"); None })
-            .and_then(|(_, data)| Some(data.clone()))
+            .map(|(_, data)| data.clone())
+            .ok_or_else(|| {
+                LoadError::new(LoadErrorKind::NotFound, "File not found in archive")
+                    .with_filename(filename)
+            })
     }
 
     /// Returns an EQWld object representing a WLD file
-    fn _get_wld(&self, filename: &str) -> Option<Gd<S3DWld>> {
-        let data = self._get(filename)?;
+    fn _get_wld(&self, filename: &str) -> Result<Gd<S3DWld>, LoadError> {
+        if let Some(wld_doc) = self.preloaded_wlds.get(filename) {
+            let mut wld: Gd<S3DWld> = Gd::default();
+            wld.bind_mut().load_doc(wld_doc.clone());
+            return Ok(wld);
+        }
+        let data = self._try_get(filename)?;
         let mut wld: Gd<S3DWld> = Gd::default();
-        wld.bind_mut().load(data);
-        Some(wld)
+        wld.bind_mut()
+            .load(data)
+            .map_err(|e| e.with_filename(filename))?;
+        Ok(wld)
+    }
+
+    /// Calls `_get_wld`, printing and recording any error so it can be retrieved with `last_error()`.
+    fn _get_wld_checked(&mut self, filename: &str) -> Option<Gd<S3DWld>> {
+        match self._get_wld(filename) {
+            Ok(wld) => {
+                self.last_error = None;
+                Some(wld)
+            }
+            Err(e) => {
+                godot_error!("{e}");
+                self.last_error = Some(e);
+                None
+            }
+        }
     }
 }
+/// Reads the archive index and file data, without creating any Godot objects.
+fn read_archive<R: Read + Seek>(filename: &str, reader: R) -> Result<EqArchive, LoadError> {
+    EqArchive::read(reader).map_err(|e| {
+        LoadError::new(
+            LoadErrorKind::InvalidArchive,
+            format!("Failed to parse S3D archive: {e:?}"),
+        )
+        .with_filename(filename)
+    })
+}
+
+/// The file stem of the archive, e.g. "rivervale"
+fn archive_name(filename: &str) -> String {
+    Path::new(&filename)
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .map(String::from)
+        .unwrap_or_default()
+}
+
+/// Reads an archive that is already in memory, decodes all of its images and parses all of its WLDs.
+/// This does not create any Godot objects, so it is safe to call from a worker thread.
+/// The bytes should be read with FileAccess on the main thread first, so that `res://` paths and exported packs work.
+/// `progress` is called with a stage name and the fraction of that stage that is complete.
+pub fn parse_archive(
+    filename: &str,
+    data: Vec<u8>,
+    options: TextureOptions,
+    progress: impl Fn(&str, f32) + Sync,
+) -> Result<ParsedArchive, LoadError> {
+    progress("archive", 0.);
+    let archive = read_archive(filename, Cursor::new(data))?;
+    progress("archive", 1.);
+
+    let image_files: Vec<&(String, Vec<u8>)> = archive
+        .iter()
+        .filter(|(name, _)| is_image_filename(name))
+        .collect();
+    let image_count = image_files.len();
+    let decoded_count = AtomicUsize::new(0);
+    progress("textures", 0.);
+
+    // Images are independent of each other, so they are spread across all available cores.
+    let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = image_count.div_ceil(num_threads).max(1);
+    let images: HashMap<String, DecodedImage> = thread::scope(|scope| {
+        let handles: Vec<_> = image_files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let progress = &progress;
+                let decoded_count = &decoded_count;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter_map(|(name, data)| {
+                            let decoded = decode_image(name, data.clone(), &options)
+                                .map_err(|e| godot_error!("Failed to load image from {name}: {e}"))
+                                .ok();
+                            let done = decoded_count.fetch_add(1, Ordering::Relaxed) + 1;
+                            progress("textures", done as f32 / image_count as f32);
+                            decoded.map(|decoded| (name.clone(), decoded))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    });
+
+    let wld_files: Vec<&(String, Vec<u8>)> = archive
+        .iter()
+        .filter(|(name, _)| name.ends_with(".wld"))
+        .collect();
+    let mut wlds = HashMap::new();
+    progress("wlds", 0.);
+    for (index, (name, data)) in wld_files.iter().enumerate() {
+        // A corrupt WLD does not fail the whole archive.  It is left out, so `get_wld` parses it again and reports the error.
+        match parse_wld(data) {
+            Ok(wld) => {
+                wlds.insert(name.clone(), wld);
+            }
+            Err(e) => godot_error!("{}", e.with_filename(name)),
+        }
+        progress("wlds", (index + 1) as f32 / wld_files.len() as f32);
+    }
+
+    Ok(ParsedArchive {
+        archive,
+        images,
+        options,
+        wlds,
+    })
+}
+
 // #[godot_api]
 // impl RefCountedVirtual for EQArchive {
 //     fn init(base: Base<RefCounted>) -> Self {
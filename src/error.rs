@@ -0,0 +1,135 @@
+use godot::classes::RefCounted;
+use godot::prelude::*;
+use std::fmt;
+
+/// The broad category of a loading failure.
+/// These values are exposed to GDScript as the `KIND_*` constants on `EQLoadError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadErrorKind {
+    /// The file could not be opened or read from disk.
+    Io = 1,
+    /// The requested file does not exist within the archive.
+    NotFound = 2,
+    /// The archive itself is corrupt, truncated, or not an S3D/PFS archive.
+    InvalidArchive = 3,
+    /// The WLD data could not be parsed.
+    InvalidWld = 4,
+    /// A fragment index is out of bounds, or the fragment is not of the expected type.
+    InvalidFragment = 5,
+}
+
+/// A recoverable error raised while loading archives, WLDs or fragments.
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub kind: LoadErrorKind,
+    pub message: String,
+    /// The archive or file within the archive that caused the error, if known.
+    pub filename: Option<String>,
+    /// The offending fragment index, if known.  Note that indices begin at 1.
+    pub fragment_index: Option<u32>,
+}
+
+impl LoadError {
+    pub fn new(kind: LoadErrorKind, message: impl Into<String>) -> Self {
+        LoadError {
+            kind,
+            message: message.into(),
+            filename: None,
+            fragment_index: None,
+        }
+    }
+
+    pub fn with_filename(mut self, filename: &str) -> Self {
+        self.filename = Some(String::from(filename));
+        self
+    }
+
+    pub fn with_fragment_index(mut self, index: u32) -> Self {
+        self.fragment_index = Some(index);
+        self
+    }
+
+    /// Wrap this error in a Godot object so it can be handed to GDScript.
+    pub fn to_gd(&self) -> Gd<EQLoadError> {
+        let mut obj = Gd::<EQLoadError>::default();
+        obj.bind_mut().load(self.clone());
+        obj
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(filename) = &self.filename {
+            write!(f, " (file: {filename})")?;
+        }
+        if let Some(index) = self.fragment_index {
+            write!(f, " (fragment: {index})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes why a load failed.  Returned by the `last_error()` methods of
+/// `EQArchiveLoader`, `EQArchive` and `S3DWld` after a call returned null.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct EQLoadError {
+    base: Base<RefCounted>,
+    error: Option<LoadError>,
+}
+
+#[godot_api]
+impl EQLoadError {
+    #[constant]
+    const KIND_IO: i32 = LoadErrorKind::Io as i32;
+    #[constant]
+    const KIND_NOT_FOUND: i32 = LoadErrorKind::NotFound as i32;
+    #[constant]
+    const KIND_INVALID_ARCHIVE: i32 = LoadErrorKind::InvalidArchive as i32;
+    #[constant]
+    const KIND_INVALID_WLD: i32 = LoadErrorKind::InvalidWld as i32;
+    #[constant]
+    const KIND_INVALID_FRAGMENT: i32 = LoadErrorKind::InvalidFragment as i32;
+
+    /// A human-readable description of the error.
+    #[func]
+    pub fn message(&self) -> GString {
+        GString::from(&self.error().message)
+    }
+
+    /// One of the `KIND_*` constants.
+    #[func]
+    pub fn kind(&self) -> i32 {
+        self.error().kind as i32
+    }
+
+    /// The archive or file within the archive that caused the error, or an empty string.
+    #[func]
+    pub fn filename(&self) -> GString {
+        GString::from(self.error().filename.as_deref().unwrap_or(""))
+    }
+
+    /// The offending fragment index, or 0 if the error is not related to a fragment.
+    #[func]
+    pub fn fragment_index(&self) -> u32 {
+        self.error().fragment_index.unwrap_or(0)
+    }
+
+    #[func]
+    pub fn as_string(&self) -> GString {
+        GString::from(self.error().to_string())
+    }
+}
+
+impl EQLoadError {
+    pub fn load(&mut self, error: LoadError) {
+        self.error = Some(error);
+    }
+
+    fn error(&self) -> &LoadError {
+        self.error
+            .as_ref()
+            .expect("This class must be initialized with the load() function.")
+    }
+}
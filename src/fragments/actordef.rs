@@ -1,9 +1,10 @@
 use godot::classes::RefCounted;
 use godot::prelude::*;
-use libeq_wld::parser::{FragmentRef, DmSprite, ActorDef, WldDoc};
+use libeq_wld::parser::{FragmentRef, DmSprite, ActorDef, HierarchicalSprite, WldDoc};
 use std::sync::Arc;
 extern crate owning_ref;
-use super::{create_fragment_ref, S3DFragment, S3DMesh};
+use crate::error::LoadError;
+use super::{create_fragment_ref, is_collision_volume, S3DFragment, S3DHierSprite, S3DMesh};
 use owning_ref::ArcRef;
 #[cfg(feature = "serde")]
 use super::frag_to_dict;
@@ -17,8 +18,9 @@ pub struct S3DActorDef {
 }
 
 impl S3DFragment for S3DActorDef {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) {
-        self.fragment = Some(create_fragment_ref(wld.clone(), index));
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
     }
     
 }
@@ -44,6 +46,10 @@ impl S3DActorDef {
         )
     }
 
+    /// Returns the Godot class for each fragment the actor references:
+    /// S3DMesh for meshes, S3DHierSprite for skeletons, S3DSprite3D for cameras, S3DSprite4D for sprites that cycle
+    /// through other sprites, and S3DBlitSprite or S3DSprite2D for flat sprites.
+    /// Unsupported fragments are returned as S3DUnknownFragment.
     #[func]
     fn references(&self) -> Array<Variant> {
         let wld = self.get_wld();
@@ -58,7 +64,7 @@ impl S3DActorDef {
     }
 
     #[func]
-    fn meshes(&self) -> Array<Gd<S3DMesh>> {
+    pub fn meshes(&self) -> Array<Gd<S3DMesh>> {
         let wld = self.get_wld();
         self.get_frag()
             .fragment_references
@@ -73,6 +79,34 @@ impl S3DActorDef {
             .collect()
     }
 
+    /// The simplified collision volumes for the actor: S3DPolyhedron, S3DSphereList or S3DSphere.
+    /// Includes volumes referenced directly by the actor and those of its skeletons.
+    #[func]
+    fn collision_volumes(&self) -> Array<Variant> {
+        let wld = self.get_wld();
+        let mut volumes: Array<Variant> = self
+            .get_frag()
+            .fragment_references
+            .iter()
+            .filter(|&&index| is_collision_volume(wld, index))
+            .map(|&index| gd_from_frag(wld, index))
+            .collect();
+        for fragment_ref in self.get_frag().fragment_references.iter() {
+            let hiersprite_ref = FragmentRef::<HierarchicalSprite>::new(*fragment_ref as i32);
+            let Some(hiersprite) = wld
+                .get(&hiersprite_ref)
+                .and_then(|reference| S3DHierSprite::from_reference(wld, reference))
+            else {
+                continue;
+            };
+            let volume = hiersprite.bind().collision_volume();
+            if !volume.is_nil() {
+                volumes.push(&volume);
+            }
+        }
+        volumes
+    }
+
     #[cfg(feature = "serde")]
     #[func]
     pub fn as_dict(&self) -> Dictionary {
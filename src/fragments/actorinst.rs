@@ -4,6 +4,7 @@ use libeq_wld::parser::{Location, Actor, WldDoc};
 use std::sync::Arc;
 extern crate owning_ref;
 use crate::wld::gd_from_frag;
+use crate::error::LoadError;
 use super::{create_fragment_ref, S3DFragment};
 use crate::util::{u32_to_color, wld_degrees_rot_to_quat, wld_f32_pos_to_gd};
 use owning_ref::ArcRef;
@@ -120,9 +121,10 @@ impl S3DActorInstance {
 }
 
 impl S3DFragment for S3DActorInstance {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) {
-        self.fragment = Some(create_fragment_ref(wld.clone(), index));
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
         self.index = index;
+        Ok(())
     }
 }
 
@@ -0,0 +1,356 @@
+use godot::classes::RefCounted;
+use godot::prelude::*;
+use libeq_wld::parser::{FragmentType, MaterialDef, WldDoc};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use crate::error::{LoadError, LoadErrorKind};
+use crate::wld::gd_from_frag_type;
+use super::S3DMesh;
+
+/// The body part of head materials and meshes.
+const HEAD_PART: &str = "HE";
+
+/// The body parts of character materials, and the tint slot each belongs to.
+/// The tint slots are the equipment slots whose dye color the client applies to the material.
+const BODY_PARTS: [(&str, i32); 7] = [
+    ("HE", S3DCharacterVariants::TINT_SLOT_HEAD),
+    ("CH", S3DCharacterVariants::TINT_SLOT_CHEST),
+    ("UA", S3DCharacterVariants::TINT_SLOT_ARMS),
+    ("FA", S3DCharacterVariants::TINT_SLOT_WRISTS),
+    ("HN", S3DCharacterVariants::TINT_SLOT_HANDS),
+    ("LG", S3DCharacterVariants::TINT_SLOT_LEGS),
+    ("FT", S3DCharacterVariants::TINT_SLOT_FEET),
+];
+
+/// A character material, parsed from a name like HUMCH0102_MDF:
+/// the actor tag (HUM), the body part (CH), the texture set (01) and the piece of the body part (02).
+/// For heads, the last two digits are the face (a single digit) and the piece (a single digit), e.g. HUMHE0031_MDF.
+#[derive(Debug, Clone, PartialEq)]
+struct CharacterMaterial {
+    name: String,
+    part: String,
+    texture_set: i32,
+    face: i32,
+    piece: i32,
+}
+
+impl CharacterMaterial {
+    fn parse(tag: &str, name: &str) -> Option<Self> {
+        let code = name.strip_prefix(tag)?.strip_suffix("_MDF")?;
+        let part = code.get(0..2)?;
+        let digits = code.get(2..)?;
+        if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        if !BODY_PARTS.iter().any(|(body_part, _)| *body_part == part) {
+            return None;
+        }
+        let number = |range: std::ops::Range<usize>| digits[range].parse::<i32>().ok();
+        let (face, piece) = if part == HEAD_PART {
+            (number(2..3)?, number(3..4)?)
+        } else {
+            (0, number(2..4)?)
+        };
+        Some(Self {
+            name: String::from(name),
+            part: String::from(part),
+            texture_set: number(0..2)?,
+            face,
+            piece,
+        })
+    }
+
+    /// The name of the same piece in another texture set (and for heads, another face).
+    fn variant_name(&self, tag: &str, texture_set: i32, face: i32) -> String {
+        if self.part == HEAD_PART {
+            format!("{tag}{}{texture_set:02}{face}{}_MDF", self.part, self.piece)
+        } else {
+            format!("{tag}{}{texture_set:02}{:02}_MDF", self.part, self.piece)
+        }
+    }
+
+    fn tint_slot(&self) -> i32 {
+        BODY_PARTS
+            .iter()
+            .find(|(part, _)| *part == self.part)
+            .map_or(-1, |(_, slot)| *slot)
+    }
+}
+
+/// Returns the number of a head mesh from a name like HUMHE01_DMSPRITEDEF.
+fn head_mesh_number(tag: &str, name: &str) -> Option<i32> {
+    let digits = name
+        .strip_prefix(tag)?
+        .strip_prefix(HEAD_PART)?
+        .strip_suffix("_DMSPRITEDEF")?;
+    if digits.len() != 2 {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// The appearance variations of a character, from `S3DHierSprite.character_variants()`.
+///
+/// Characters have a default look made of texture set 0, face 0 and the head mesh among their default meshes.
+/// Other looks swap materials and the head mesh by name: the materials of texture set 1 replace those of texture set 0 (e.g. HUMCH0001_MDF becomes HUMCH0101_MDF),
+/// faces replace the head materials, and alternate head meshes (HUMHE01_DMSPRITEDEF...) replace the default head.
+/// Only variations found in the character's WLD are returned - robes and armor from other archives are not included.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DCharacterVariants {
+    base: Base<RefCounted>,
+    wld: Option<Arc<WldDoc>>,
+    tag: String,
+    materials: Vec<CharacterMaterial>,
+    /// The fragment indices of the character's default meshes, from `S3DHierSprite.meshes()`.
+    mesh_indices: Vec<u32>,
+    /// The number and fragment index of each head mesh.
+    head_meshes: Vec<(i32, u32)>,
+}
+
+#[godot_api]
+impl S3DCharacterVariants {
+    #[constant]
+    pub const TINT_SLOT_HEAD: i32 = 0;
+    #[constant]
+    pub const TINT_SLOT_CHEST: i32 = 1;
+    #[constant]
+    pub const TINT_SLOT_ARMS: i32 = 2;
+    #[constant]
+    pub const TINT_SLOT_WRISTS: i32 = 3;
+    #[constant]
+    pub const TINT_SLOT_HANDS: i32 = 4;
+    #[constant]
+    pub const TINT_SLOT_LEGS: i32 = 5;
+    #[constant]
+    pub const TINT_SLOT_FEET: i32 = 6;
+
+    #[func]
+    pub fn tag(&self) -> GString {
+        GString::from(&self.tag)
+    }
+
+    /// The texture sets the character has materials for, e.g. [0, 1, 2, 3] for cloth, leather, chain and plate.
+    #[func]
+    pub fn texture_sets(&self) -> PackedInt32Array {
+        self.materials
+            .iter()
+            .map(|material| material.texture_set)
+            .collect::<BTreeSet<i32>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The faces the character has head materials for.
+    #[func]
+    pub fn faces(&self) -> PackedInt32Array {
+        self.materials
+            .iter()
+            .filter(|material| material.part == HEAD_PART)
+            .map(|material| material.face)
+            .collect::<BTreeSet<i32>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The numbers of the head meshes, e.g. [0, 1, 2] for HUMHE00, HUMHE01 and HUMHE02.
+    #[func]
+    pub fn head_meshes(&self) -> PackedInt32Array {
+        self.head_meshes.iter().map(|(number, _)| *number).collect()
+    }
+
+    /// Every character material, as Dictionaries with the keys `name`, `part` (e.g. "CH"), `texture_set`, `face`,
+    /// `piece` and `tint_slot` (one of the `TINT_SLOT_*` constants).
+    #[func]
+    pub fn materials(&self) -> Array<Dictionary> {
+        self.materials
+            .iter()
+            .map(|material| {
+                let mut dict = Dictionary::new();
+                dict.set("name", GString::from(&material.name));
+                dict.set("part", GString::from(&material.part));
+                dict.set("texture_set", material.texture_set);
+                dict.set("face", material.face);
+                dict.set("piece", material.piece);
+                dict.set("tint_slot", material.tint_slot());
+                dict
+            })
+            .collect()
+    }
+
+    /// The tint slot of each material (one of the `TINT_SLOT_*` constants), keyed by material name.
+    /// Multiply a material's color by the dye color of its slot to tint robes and armor.
+    #[func]
+    pub fn tint_slots(&self) -> Dictionary {
+        self.materials
+            .iter()
+            .map(|material| (GString::from(&material.name), material.tint_slot()))
+            .collect()
+    }
+
+    /// Maps the name of each default material (texture set 0, face 0) to the name of the material to use instead
+    /// for the given texture set and face.  Materials the character has no variation of are left out, so they keep their default.
+    /// Use this to look up materials when building the meshes, e.g. with `S3DMesh.face_material_groups()`.
+    #[func]
+    pub fn material_remap(&self, texture_set: i32, face: i32) -> Dictionary {
+        self.materials
+            .iter()
+            .filter(|material| material.texture_set == 0 && material.face == 0)
+            .filter_map(|material| {
+                let variant_name = material.variant_name(&self.tag, texture_set, face);
+                if variant_name == material.name || !self.has_material(&variant_name) {
+                    return None;
+                }
+                Some((GString::from(&material.name), GString::from(variant_name)))
+            })
+            .collect()
+    }
+
+    /// The meshes for the given head mesh number: the character's meshes, with the default head replaced.
+    /// If the character has no head mesh with that number, the default head is kept.
+    #[func]
+    pub fn meshes(&self, head: i32) -> Array<Gd<S3DMesh>> {
+        let Some(wld) = self.get_wld() else {
+            return Array::new();
+        };
+        let default_head = self.default_head_mesh_index();
+        let head = self.head_mesh_index(head);
+        self.mesh_indices
+            .iter()
+            .map(|&index| match (default_head, head) {
+                (Some(default_head), Some(head)) if index == default_head => head,
+                _ => index,
+            })
+            .filter_map(|index| {
+                gd_from_frag_type::<S3DMesh>(wld, index)
+                    .map_err(|e| godot_error!("{e}"))
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Everything needed to build one variation of the character: a Dictionary with the keys
+    /// `materials` (from `material_remap()`) and `meshes` (from `meshes()`).
+    #[func]
+    pub fn variant(&self, texture_set: i32, face: i32, head: i32) -> Dictionary {
+        let mut dict = Dictionary::new();
+        dict.set("materials", self.material_remap(texture_set, face));
+        dict.set("meshes", self.meshes(head));
+        dict
+    }
+}
+
+impl S3DCharacterVariants {
+    pub fn load(&mut self, wld: &Arc<WldDoc>, tag: &str, mesh_indices: Vec<u32>) {
+        self.materials = wld
+            .fragment_iter::<MaterialDef>()
+            .filter_map(|material| {
+                let name = wld.get_string(material.name_reference)?;
+                CharacterMaterial::parse(tag, name)
+            })
+            .collect();
+        self.head_meshes = wld
+            .iter()
+            .enumerate()
+            .filter_map(|(index, fragment)| {
+                let name_reference = match fragment.as_ref() {
+                    FragmentType::DmSpriteDef(mesh) => mesh.name_reference,
+                    FragmentType::DmSpriteDef2(mesh) => mesh.name_reference,
+                    _ => return None,
+                };
+                let number = head_mesh_number(tag, wld.get_string(name_reference)?)?;
+                Some((number, index as u32 + 1))
+            })
+            .collect();
+        self.head_meshes.sort();
+        self.mesh_indices = mesh_indices;
+        self.tag = String::from(tag);
+        self.wld = Some(wld.clone());
+    }
+
+    /// The WLD of the character, or None (with an error) if this was not created by `S3DHierSprite.character_variants()`.
+    fn get_wld(&self) -> Option<&Arc<WldDoc>> {
+        let wld = self.wld.as_ref();
+        if wld.is_none() {
+            godot_error!(
+                "{}",
+                LoadError::new(
+                    LoadErrorKind::InvalidFragment,
+                    "S3DCharacterVariants must be created with S3DHierSprite.character_variants()",
+                )
+            );
+        }
+        wld
+    }
+
+    fn has_material(&self, name: &str) -> bool {
+        self.materials.iter().any(|material| material.name == name)
+    }
+
+    /// The fragment index of the head mesh among the character's default meshes, whatever its number.
+    fn default_head_mesh_index(&self) -> Option<u32> {
+        self.head_meshes
+            .iter()
+            .map(|(_, index)| *index)
+            .find(|index| self.mesh_indices.contains(index))
+    }
+
+    fn head_mesh_index(&self, head: i32) -> Option<u32> {
+        self.head_meshes
+            .iter()
+            .find(|(number, _)| *number == head)
+            .map(|(_, index)| *index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_body_materials() {
+        assert_eq!(
+            CharacterMaterial::parse("HUM", "HUMCH0102_MDF"),
+            Some(CharacterMaterial {
+                name: String::from("HUMCH0102_MDF"),
+                part: String::from("CH"),
+                texture_set: 1,
+                face: 0,
+                piece: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_head_materials() {
+        assert_eq!(
+            CharacterMaterial::parse("HUM", "HUMHE0031_MDF"),
+            Some(CharacterMaterial {
+                name: String::from("HUMHE0031_MDF"),
+                part: String::from("HE"),
+                texture_set: 0,
+                face: 3,
+                piece: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_other_materials() {
+        assert_eq!(CharacterMaterial::parse("HUM", "ELFCH0102_MDF"), None);
+        assert_eq!(CharacterMaterial::parse("HUM", "HUMXX0102_MDF"), None);
+        assert_eq!(CharacterMaterial::parse("HUM", "HUMCH012_MDF"), None);
+        assert_eq!(CharacterMaterial::parse("HUM", "HUMCH01A2_MDF"), None);
+        assert_eq!(CharacterMaterial::parse("HUM", "HUMCH0102_SPRITE"), None);
+    }
+
+    #[test]
+    fn parses_head_mesh_numbers() {
+        assert_eq!(head_mesh_number("HUM", "HUMHE00_DMSPRITEDEF"), Some(0));
+        assert_eq!(head_mesh_number("HUM", "HUMHE12_DMSPRITEDEF"), Some(12));
+        assert_eq!(head_mesh_number("HUM", "HUMHE1_DMSPRITEDEF"), None);
+        assert_eq!(head_mesh_number("HUM", "HUMCH01_DMSPRITEDEF"), None);
+        assert_eq!(head_mesh_number("HUM", "ELFHE01_DMSPRITEDEF"), None);
+        assert_eq!(head_mesh_number("HUM", "HUM_DMSPRITEDEF"), None);
+    }
+}
@@ -0,0 +1,327 @@
+use godot::classes::{CollisionShape3D, ConvexPolygonShape3D, RefCounted, Shape3D, SphereShape3D};
+use godot::prelude::*;
+use libeq_wld::parser::{
+    FragmentRef, FragmentType, Polyhedron, PolyhedronDef, Sphere, SphereList, SphereListDef, WldDoc,
+};
+use std::sync::Arc;
+extern crate owning_ref;
+use crate::error::LoadError;
+use crate::wld::gd_from_frag_type;
+use super::{create_fragment_ref, S3DFragment};
+use crate::util::{wld_f32_pos_to_gd, WORLD_SCALE};
+use owning_ref::ArcRef;
+#[cfg(feature = "serde")]
+use super::frag_to_dict;
+
+/// Returns true if the fragment at the given index (starting at 1) is a collision volume
+/// that `gd_from_frag` wraps as S3DPolyhedron, S3DSphereList or S3DSphere.
+pub fn is_collision_volume(wld: &WldDoc, index: u32) -> bool {
+    index
+        .checked_sub(1)
+        .and_then(|i| wld.at(i as usize))
+        .is_some_and(|fragment| {
+            matches!(
+                fragment,
+                FragmentType::PolyhedronDef(_)
+                    | FragmentType::Polyhedron(_)
+                    | FragmentType::SphereListDef(_)
+                    | FragmentType::SphereList(_)
+                    | FragmentType::Sphere(_)
+            )
+        })
+}
+
+/// Wraps a shape in a CollisionShape3D node at the given position.
+fn collision_shape(name: &str, shape: Gd<Shape3D>, position: Vector3) -> Gd<CollisionShape3D> {
+    let mut node = CollisionShape3D::new_alloc();
+    node.set_name(name);
+    node.set_shape(&shape);
+    node.set_position(position);
+    node
+}
+
+/// A wrapper around `POLYHEDRONDEFINITION`, a simplified convex collision volume for an actor.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DPolyhedron {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, PolyhedronDef>>,
+}
+
+impl S3DFragment for S3DPolyhedron {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DPolyhedron {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The bounding radius of the volume, in Godot units.
+    #[func]
+    pub fn bounding_radius(&self) -> f32 {
+        self.get_frag().bounding_radius * WORLD_SCALE
+    }
+
+    /// The vertices of the volume, in Godot coordinates.
+    #[func]
+    pub fn vertices(&self) -> PackedVector3Array {
+        let frag = self.get_frag();
+        let scale = if frag.scale_factor > 0. { frag.scale_factor } else { 1. };
+        frag.vertices
+            .iter()
+            .map(|v| wld_f32_pos_to_gd(v) * scale)
+            .collect()
+    }
+
+    /// The faces of the volume, as arrays of indices into `vertices()`.
+    #[func]
+    pub fn faces(&self) -> Array<PackedInt32Array> {
+        self.get_frag()
+            .faces
+            .iter()
+            .map(|face| face.vertex_indexes.iter().map(|&i| i as i32).collect())
+            .collect()
+    }
+
+    /// Creates a ConvexPolygonShape3D from the vertices of the volume.
+    #[func]
+    pub fn to_shape(&self) -> Gd<ConvexPolygonShape3D> {
+        let mut shape = ConvexPolygonShape3D::new_gd();
+        shape.set_points(&self.vertices());
+        shape
+    }
+
+    /// Creates a CollisionShape3D holding `to_shape()`, ready to add to a physics body.
+    #[func]
+    pub fn to_collision_shapes(&self) -> Array<Gd<CollisionShape3D>> {
+        array![&collision_shape(&self.name().to_string(), self.to_shape().upcast(), Vector3::ZERO)]
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DPolyhedron {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &PolyhedronDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    /// Creates an S3DPolyhedron from a `POLYHEDRON` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, polyhedron: &Polyhedron) -> Option<Gd<Self>> {
+        match polyhedron.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DPolyhedron>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
+
+/// A wrapper around `SPHERELISTDEFINITION`, a set of spheres that approximate the shape of an actor.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DSphereList {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, SphereListDef>>,
+}
+
+impl S3DFragment for S3DSphereList {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DSphereList {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The centers of the spheres, in Godot coordinates.
+    #[func]
+    pub fn centers(&self) -> PackedVector3Array {
+        let scale = self.scale();
+        self.get_frag()
+            .spheres
+            .iter()
+            .map(|&(x, y, z, _)| wld_f32_pos_to_gd(&(x, y, z)) * scale)
+            .collect()
+    }
+
+    /// The radii of the spheres, in Godot units.
+    #[func]
+    pub fn radii(&self) -> PackedFloat32Array {
+        let scale = self.scale();
+        self.get_frag()
+            .spheres
+            .iter()
+            .map(|&(_, _, _, radius)| radius * scale * WORLD_SCALE)
+            .collect()
+    }
+
+    /// Creates a CollisionShape3D with a SphereShape3D for each sphere, positioned at its center.
+    #[func]
+    pub fn to_collision_shapes(&self) -> Array<Gd<CollisionShape3D>> {
+        let name = self.name().to_string();
+        self.centers()
+            .as_slice()
+            .iter()
+            .zip(self.radii().as_slice())
+            .enumerate()
+            .map(|(i, (&center, &radius))| {
+                let mut shape = SphereShape3D::new_gd();
+                shape.set_radius(radius);
+                collision_shape(&format!("{name}_{i}"), shape.upcast(), center)
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DSphereList {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &SphereListDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    fn scale(&self) -> f32 {
+        match self.get_frag().scale_factor {
+            Some(scale) if scale > 0. => scale,
+            _ => 1.,
+        }
+    }
+
+    /// Creates an S3DSphereList from a `SPHERELIST` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, sphere_list: &SphereList) -> Option<Gd<Self>> {
+        match sphere_list.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DSphereList>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
+
+/// A wrapper around `SPHERE`, a single sphere centered on an actor.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DSphere {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, Sphere>>,
+}
+
+impl S3DFragment for S3DSphere {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DSphere {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    /// The radius of the sphere, in Godot units.
+    #[func]
+    pub fn radius(&self) -> f32 {
+        self.get_frag().radius * WORLD_SCALE
+    }
+
+    #[func]
+    pub fn to_shape(&self) -> Gd<SphereShape3D> {
+        let mut shape = SphereShape3D::new_gd();
+        shape.set_radius(self.radius());
+        shape
+    }
+
+    /// Creates a CollisionShape3D holding `to_shape()`, ready to add to a physics body.
+    #[func]
+    pub fn to_collision_shapes(&self) -> Array<Gd<CollisionShape3D>> {
+        array![&collision_shape(&self.name().to_string(), self.to_shape().upcast(), Vector3::ZERO)]
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DSphere {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &Sphere {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+}
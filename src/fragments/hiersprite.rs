@@ -1,24 +1,92 @@
-use godot::classes::animation::{InterpolationType, TrackType};
+use godot::builtin::math::ApproxEq;
+use godot::classes::animation::{InterpolationType, LoopMode, TrackType};
 
-use godot::classes::{Animation, AnimationLibrary, RefCounted};
+use godot::classes::{Animation, AnimationLibrary, BoneAttachment3D, MeshInstance3D, RefCounted, Skeleton3D, Skin};
 use godot::prelude::*;
 use libeq_wld::parser::{
     Dag, FragmentRef, FragmentType, FrameTransform, HierarchicalSprite, HierarchicalSpriteDef, LegacyFrameTransform, MaterialDef, StringReference, Track, TrackDef, WldDoc
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 extern crate owning_ref;
 #[cfg(feature = "serde")]
 use super::frag_to_dict;
-use super::{create_fragment_ref, S3DFragment, S3DMesh};
+use crate::animation_names::EQAnimationNames;
+use crate::error::{LoadError, LoadErrorKind};
+use crate::util::animation::AnimationNames;
+use super::{create_fragment_ref, is_collision_volume, S3DActorDef, S3DCharacterVariants, S3DFragment, S3DMesh};
 use crate::util::wld_f32_pos_to_gd;
-use crate::wld::{gd_from_frag, gd_from_frag_type};
+use crate::wld::{gd_from_frag, gd_from_frag_type, S3DWld};
 use owning_ref::ArcRef;
 
 /// The rest animation is unnamed in the EQ data.  We need to give it a name.
 const REST_ANIMATION_NAME: &str = "REST";
 /// The root bone of the skeleton is unnamed in the EQ data.  We need to give it a name.
 const ROOT_BONE_NAME: &str = "ROOT";
+/// The bones where the client mounts held and worn items, as (slot, bone name).
+/// The bone names are generic (see `S3DBone.name()`), so they are the same for every race.
+/// This is the default table - it can be replaced with `S3DHierSprite.set_attachment_points`.
+const ATTACHMENT_POINTS: [(&str, &str); 4] = [
+    ("primary", "R_POINT"),
+    ("secondary", "L_POINT"),
+    ("shield", "SHIELD_POINT"),
+    ("head", "HEAD_POINT"),
+];
+
+fn default_attachment_points() -> Vec<(String, String)> {
+    ATTACHMENT_POINTS
+        .iter()
+        .map(|(slot, point_name)| (String::from(*slot), String::from(*point_name)))
+        .collect()
+}
+
+/// The length of the actor tags that can be left at the start of bone names, e.g. ELF in ELFR_POINT.
+const ACTOR_TAG_LEN: usize = 3;
+
+/// Returns the index of the bone for an attachment point, from the bone names of `S3DHierSprite._bone_names()`.
+/// Names are compared without case.  Bone names usually have the skeleton's tag removed already, but a bone may still
+/// carry another actor tag in front of the point name, so that is accepted if no bone matches exactly.
+fn find_attachment_bone(bone_names: &[String], point_name: &str) -> Option<usize> {
+    let point_name = point_name.to_uppercase();
+    let exact = bone_names
+        .iter()
+        .position(|bone_name| bone_name.to_uppercase() == point_name);
+    exact.or_else(|| {
+        bone_names.iter().position(|bone_name| {
+            bone_name
+                .to_uppercase()
+                .strip_suffix(point_name.as_str())
+                .is_some_and(|tag| tag.len() == ACTOR_TAG_LEN && tag.chars().all(|c| c.is_ascii_alphanumeric()))
+        })
+    })
+}
+/// 100 ms is the default delay between frames - sometimes this is explicit, sometimes it is not.
+const DEFAULT_FRAME_SLEEP: u32 = 100;
+
+/// How a track is played back, decoded from the flags of the `TRACK` fragment.
+///
+/// The `TRACK` flags are "has sleep" (0x1), "reverse" (0x2) and "interpolate" (0x4).  The only `TRACKDEF` flag (0x8) picks
+/// the format of the frame transforms, which libeq decodes into `frame_transforms` or `legacy_frame_transforms`
+/// (see the `Track` and `TrackDef` fragments in libeq_wld, and `TrackFragment` and `TrackDefFragment` in LanternExtractor).
+/// Neither fragment has a loop flag, so whether an animation loops comes from the `EQAnimationNames` table.
+struct TrackPlayback {
+    /// If false, the track snaps from frame to frame.  Most single-frame tracks have this unset.
+    interpolate: bool,
+    /// If true, the frames of the `TRACKDEF` are played in reverse.
+    reverse: bool,
+    /// The delay between frames, in seconds.
+    secs_per_frame: f64,
+}
+
+impl TrackPlayback {
+    fn from_track(track: &Track) -> Self {
+        Self {
+            interpolate: track.flags.interpolate(),
+            reverse: track.flags.reverse(),
+            secs_per_frame: track.sleep.unwrap_or(DEFAULT_FRAME_SLEEP) as f64 * 0.001,
+        }
+    }
+}
 
 pub struct Bone {
     full_name: String,
@@ -73,6 +141,12 @@ impl S3DBone {
         self.bone().rest_quaternion
     }
 
+    /// The rest pose of the bone relative to its parent, for `Skeleton3D.set_bone_rest()`
+    #[func]
+    pub fn rest_transform(&self) -> Transform3D {
+        Transform3D::new(Basis::from_quat(self.bone().rest_quaternion), self.bone().rest_position)
+    }
+
     /// If there is an attachment, return it as a Godot class reprsentation of the fragment.  It is usually a MeshReference
     #[func]
     pub fn attachment(&self) -> Variant {
@@ -103,12 +177,67 @@ impl S3DBone {
 pub struct S3DHierSprite {
     base: Base<RefCounted>,
     fragment: Option<ArcRef<WldDoc, HierarchicalSpriteDef>>,
+    animation_names: AnimationNames,
+    /// The bone of each attachment slot, as (slot, bone name).
+    #[init(val = default_attachment_points())]
+    attachment_points: Vec<(String, String)>,
 }
 
 impl S3DFragment for S3DHierSprite {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) {
-        self.fragment = Some(create_fragment_ref(wld.clone(), index));
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        let fragment: ArcRef<WldDoc, HierarchicalSpriteDef> = create_fragment_ref(wld.clone(), index)?;
+        validate_dags(wld, &fragment).map_err(|message| {
+            LoadError::new(LoadErrorKind::InvalidFragment, message).with_fragment_index(index)
+        })?;
+        self.fragment = Some(fragment);
+        Ok(())
+    }
+}
+
+/// Checks that every DAG has a name and a rest track, and that its children are DAGs of the same skeleton.
+/// The rest of `S3DHierSprite` relies on this, so a malformed skeleton is reported when it is loaded.
+fn validate_dags(wld: &WldDoc, frag: &HierarchicalSpriteDef) -> Result<(), String> {
+    for (index, dag) in frag.dags.iter().enumerate() {
+        if wld.get_string(StringReference::new(dag.name_reference)).is_none() {
+            return Err(format!("DAG {index} has no name"));
+        }
+        if wld.get(&FragmentRef::<Track>::new(dag.track_reference as i32)).is_none() {
+            return Err(format!("DAG {index} does not reference a valid TRACK"));
+        }
+        if let Some(sub_dag) = dag.sub_dags.iter().find(|&&sub_dag| sub_dag as usize >= frag.dags.len()) {
+            return Err(format!(
+                "DAG {index} has child {sub_dag}, but the skeleton only has {} DAGs",
+                frag.dags.len()
+            ));
+        }
     }
+    Ok(())
+}
+
+/// Renames a track for another actor tag, e.g. HUM_BL_R_TRACK becomes ELF_BL_R_TRACK for the tag ELF.
+/// Only the tag at the start of the name is replaced.  Returns None if the name does not start with `own_tag`.
+fn retag_track_name(track_name: &str, own_tag: &str, tag: &str) -> Option<String> {
+    track_name
+        .strip_prefix(own_tag)
+        .map(|rest| format!("{tag}{rest}"))
+}
+
+/// The tracks that animate a bone, with the name of the animation each belongs to.
+/// Other animations of the bone end with the name of its rest track and add a prefix for the animation,
+/// e.g. D02HUM_BL_R_TRACK for the rest track HUM_BL_R_TRACK.  The rest track itself is named `REST_ANIMATION_NAME`.
+fn bone_tracks<'a>(wld: &WldDoc, tracks: &[&'a Track], rest_track_name: &str) -> Vec<(String, &'a Track)> {
+    tracks
+        .iter()
+        .filter_map(|track| {
+            let track_name = wld.get_string(track.name_reference)?;
+            let animation_name = track_name.strip_suffix(rest_track_name)?;
+            let animation_name = match animation_name {
+                "" => String::from(REST_ANIMATION_NAME),
+                animation_name => String::from(animation_name),
+            };
+            Some((animation_name, *track))
+        })
+        .collect()
 }
 
 fn parse_frame_transform(trackdef: &TrackDef, index: usize) -> (Quaternion, Vector3) {
@@ -175,7 +304,9 @@ impl S3DHierSprite {
 
         for (index, dag) in frag.dags.iter().enumerate() {
             for sub_dag in &dag.sub_dags {
-                bones[*sub_dag as usize].parent_index = index as i32;
+                if let Some(bone) = bones.get_mut(*sub_dag as usize) {
+                    bone.parent_index = index as i32;
+                }
             }
         }
 
@@ -191,6 +322,115 @@ impl S3DHierSprite {
             .collect()
     }
 
+    /// The rest pose of each bone relative to its parent, from the first frame of the rest animation.
+    /// These are in DAG order, which is the order of the bone indices used by the meshes.
+    #[func]
+    pub fn rest_transforms(&self) -> Array<Transform3D> {
+        self._rest_transforms().into_iter().collect()
+    }
+
+    /// The rest pose of each bone relative to the skeleton, in DAG order.
+    /// Pass these to `S3DMesh.bind_vertices()` or `S3DMesh.to_bind_pose_array_mesh()` to get meshes in bind space,
+    /// and use their inverses as the bind poses of a Skin.
+    #[func]
+    pub fn global_rest_transforms(&self) -> Array<Transform3D> {
+        self._global_rest_transforms().into_iter().collect()
+    }
+
+    /// The name of each bone in the skeleton built by `to_skeleton()`, in DAG order.
+    /// This is the generic bone name (see `S3DBone.name()`), but Godot requires bone names to be unique,
+    /// so repeated names get a suffix in DAG order: the second "BI_L" becomes "BI_L_2", the third "BI_L_3" and so on.
+    /// The animations from `animation_library()` use these names for their track paths.
+    #[func]
+    pub fn bone_names(&self) -> PackedStringArray {
+        self._bone_names().iter().map(GString::from).collect()
+    }
+
+    /// Builds a Skeleton3D with a bone for each DAG, named by `bone_names()`, with its parent and rest pose set.
+    /// The pose of each bone is its rest pose.  Add the meshes from `meshes()` as children,
+    /// built with `S3DMesh.to_bind_pose_array_mesh()` and given the Skin from `to_skin()`.
+    #[func]
+    pub fn to_skeleton(&self) -> Gd<Skeleton3D> {
+        let mut skeleton = Skeleton3D::new_alloc();
+        skeleton.set_name(self._name());
+        for bone_name in self._bone_names() {
+            skeleton.add_bone(bone_name.as_str());
+        }
+        for (index, parent) in self._parent_indices().into_iter().enumerate() {
+            if parent >= 0 {
+                skeleton.set_bone_parent(index as i32, parent);
+            }
+        }
+        for (index, rest) in self._rest_transforms().into_iter().enumerate() {
+            skeleton.set_bone_rest(index as i32, rest);
+        }
+        skeleton.reset_bone_poses();
+        skeleton
+    }
+
+    /// Builds a Skin with a bind pose for each bone of `to_skeleton()`, for the meshes from `meshes()` in bind space.
+    /// The binds are named, so the skin still works if bones are added to the skeleton later.
+    #[func]
+    pub fn to_skin(&self) -> Gd<Skin> {
+        let mut skin = Skin::new_gd();
+        for (bone_name, transform) in self._bone_names().iter().zip(self._global_rest_transforms()) {
+            skin.add_named_bind(bone_name.as_str(), transform.affine_inverse());
+        }
+        skin
+    }
+
+    /// The bones where items are mounted.  Keys are the slots ("primary", "secondary", "shield" and "head" by default),
+    /// and values are Dictionaries with the keys `bone_index` and `bone_name` (the name used by `to_skeleton()`).
+    /// Slots the skeleton has no bone for are left out.
+    #[func]
+    pub fn attachment_points(&self) -> Dictionary {
+        let bone_names = self._bone_names();
+        self.attachment_points
+            .iter()
+            .filter_map(|(slot, point_name)| {
+                let bone_index = find_attachment_bone(&bone_names, point_name)?;
+                let mut point = Dictionary::new();
+                point.set("bone_index", bone_index as i32);
+                point.set("bone_name", GString::from(&bone_names[bone_index]));
+                Some((GString::from(*slot), point))
+            })
+            .collect()
+    }
+
+    /// Builds a BoneAttachment3D for the given slot (see `attachment_points()`), with the meshes of `item` as children.
+    /// `item` is usually an actordef from an equipment archive (e.g. gequip.s3d), and `materials` maps its material names
+    /// to Godot Materials, as in `S3DMesh.to_array_mesh()`.  Add the result as a child of the Skeleton3D from `to_skeleton()`.
+    /// Returns null, with a warning, if the skeleton has no bone for the slot.
+    #[func]
+    pub fn to_bone_attachment(
+        &self,
+        slot: GString,
+        item: Gd<S3DActorDef>,
+        materials: Dictionary,
+    ) -> Option<Gd<BoneAttachment3D>> {
+        let Some(point) = self.attachment_points().get(slot.clone()) else {
+            godot_warn!("Skeleton {} has no bone for the attachment slot \"{slot}\"", self._name());
+            return None;
+        };
+        let point = point.try_to::<Dictionary>().ok()?;
+        let bone_name = point.get("bone_name")?.try_to::<GString>().ok()?;
+        let bone_index = point.get("bone_index")?.try_to::<i32>().ok()?;
+
+        let mut attachment = BoneAttachment3D::new_alloc();
+        attachment.set_name(format!("{slot}_{}", item.bind().name()).as_str());
+        attachment.set_bone_name(&bone_name);
+        attachment.set_bone_idx(bone_index);
+        for mesh in item.bind().meshes().iter_shared() {
+            let mesh = mesh.bind();
+            let mut mesh_inst = MeshInstance3D::new_alloc();
+            mesh_inst.set_name(&mesh.name());
+            mesh_inst.set_mesh(&mesh.to_array_mesh(materials.clone(), PackedColorArray::new(), true));
+            mesh_inst.set_position(mesh.center());
+            attachment.add_child(&mesh_inst);
+        }
+        Some(attachment)
+    }
+
     /// The meshes used by this Skeleton (usually a head and a body)
     /// These meshes should have bone assignments that correspond to the bone indices of the skeleton.
     #[func]
@@ -206,9 +446,13 @@ impl S3DHierSprite {
             .filter_map(|fragment_ref| {
                 // This could be a MeshReference or something else.
                 // We ignore everything except meshes.
-                let fragment = wld
-                    .at(*fragment_ref as usize - 1)
-                    .expect("Fragment index should exist in wld");
+                let fragment = match (*fragment_ref as usize).checked_sub(1).and_then(|i| wld.at(i)) {
+                    Some(fragment) => fragment,
+                    None => {
+                        godot_error!("Hiersprite references an invalid fragment index: {fragment_ref}");
+                        return None;
+                    }
+                };
                 match &fragment {
                     FragmentType::DmSprite(mesh_reference) => {
                         S3DMesh::from_reference(wld, mesh_reference)
@@ -222,22 +466,101 @@ impl S3DHierSprite {
             .collect()
     }
 
+    /// The simplified collision volume for this skeleton, if it has one:
+    /// S3DPolyhedron, S3DSphereList or S3DSphere.  Returns null otherwise.
+    #[func]
+    pub fn collision_volume(&self) -> Variant {
+        let wld = self.get_wld();
+        match self.get_frag().collision_volume_reference {
+            0 => Variant::nil(),
+            index if is_collision_volume(wld, index) => gd_from_frag(wld, index),
+            _ => Variant::nil(),
+        }
+    }
+
+    /// Replaces the table of attachment slots used by `attachment_points()` and `to_bone_attachment()`.
+    /// Keys are slot names and values are bone names, e.g. `{"primary": "R_POINT", "back": "BACK_POINT"}`.
+    #[func]
+    pub fn set_attachment_points(&mut self, points: Dictionary) {
+        self.attachment_points = points
+            .iter_shared()
+            .map(|(slot, point_name)| (slot.to_string(), point_name.to_string()))
+            .collect();
+    }
+
+    /// Sets the table used to give animations a category, an alias and a loop mode.  See `EQAnimationNames`.
+    #[func]
+    pub fn set_animation_names(&mut self, names: Gd<EQAnimationNames>) {
+        self.animation_names = names.bind().names();
+    }
+
     // Returns a dictionary, where keys are animation names and values are frame tranforms for each DAG
+    // Animations with an alias (see `EQAnimationNames`) are included under both names.
     #[func]
     pub fn animation_dict(&self) -> Dictionary {
-        self._animations().into_iter().collect()
+        self._named_animations(self._animations()).into_iter().collect()
     }
 
     // Returns a dictionary, where keys are animation names and values are frame tranforms for each DAG
+    // Animations with an alias (see `EQAnimationNames`) are included under both names, sharing the same Animation.
     #[func]
     pub fn animation_library(&self) -> Gd<AnimationLibrary> {
         let mut library = AnimationLibrary::new_gd();
-        for (animation_name, animation) in self._animations() {
+        for (animation_name, animation) in self._named_animations(self._animations()) {
             library.add_animation(&StringName::from(animation_name), &animation);
         }
         library
     }
 
+    /// Builds an AnimationLibrary like `animation_library()`, including animations borrowed from other actor tags.
+    /// Many models have few or no animations of their own, and the client plays another race's tracks on them instead,
+    /// sometimes from another archive such as global_chr.
+    ///
+    /// `tag_rules` maps an actor tag to the tag it borrows from, e.g. `{"ELF": "ELM", "ELM": "HUM"}`.
+    /// Rules are followed from this skeleton's tag until there is no rule for a tag.  For each tag, the tracks come
+    /// from the first WLD in `sources` that has any (include this skeleton's own WLD if it should be searched).
+    /// Tracks are matched to bones by bone name, and animations this skeleton already has are never replaced.
+    #[func]
+    pub fn shared_animation_library(&self, sources: Array<Gd<S3DWld>>, tag_rules: Dictionary) -> Gd<AnimationLibrary> {
+        let sources: Vec<Arc<WldDoc>> = sources
+            .iter_shared()
+            .map(|wld| wld.bind().get_wld().clone())
+            .collect();
+        let mut library = AnimationLibrary::new_gd();
+        let animations = self._shared_animations(&sources, &tag_rules);
+        for (animation_name, animation) in self._named_animations(animations) {
+            library.add_animation(&StringName::from(animation_name), &animation);
+        }
+        library
+    }
+
+    /// Describes each animation of this skeleton.  Keys are the raw animation names (e.g. "L01"),
+    /// and values are Dictionaries with the keys `alias` (empty if there is none), `category` and `looping`.
+    #[func]
+    pub fn animation_info(&self) -> Dictionary {
+        let names = &self.animation_names;
+        self._animation_names_from(self.get_wld(), &self._tag())
+            .into_iter()
+            .map(|animation_name| {
+                let mut info = Dictionary::new();
+                info.set("alias", GString::from(names.alias(&animation_name).unwrap_or_default()));
+                info.set("category", GString::from(names.category(&animation_name)));
+                info.set("looping", names.is_looping(&animation_name));
+                (GString::from(animation_name), info)
+            })
+            .collect()
+    }
+
+    /// The appearance variations of this character - texture sets, faces, head meshes and tint slots -
+    /// parsed from the names of its materials and meshes.  See `S3DCharacterVariants`.
+    #[func]
+    pub fn character_variants(&self) -> Gd<S3DCharacterVariants> {
+        let mesh_indices = self.meshes().iter_shared().map(|mesh| mesh.bind().index()).collect();
+        let mut variants = Gd::<S3DCharacterVariants>::default();
+        variants.bind_mut().load(self.get_wld(), &self._tag(), mesh_indices);
+        variants
+    }
+
     // Returns a list of material names that correspond to this actor, for different skin variations
     #[func]
     pub fn skin_material_names(&self) -> PackedStringArray {
@@ -268,8 +591,42 @@ impl S3DHierSprite {
 impl S3DHierSprite {
     // Returns a HashMap, where keys are animation names and values are frame tranforms for each DAG
     fn _animations(&self) -> HashMap<String, Gd<Animation>> {
-        let actor_tag = self._tag();
-        let wld = self.get_wld();
+        self._animations_from(self.get_wld(), &self._tag(), |_| true)
+    }
+
+    /// The name of the track a DAG uses at rest, renamed for the given actor tag.
+    /// When borrowing another tag's animations, the tracks are named for that tag instead, e.g. D02ELF_BL_R_TRACK.
+    /// Returns None if the track has no name, or if it has to be renamed and does not start with this skeleton's tag.
+    fn _rest_track_name(&self, dag: &Dag, tag: &str) -> Option<String> {
+        let rest_track = self.get_dag_rest_track(dag);
+        let rest_track_name = self.get_wld().get_string(rest_track.name_reference)?;
+        let own_tag = self._tag();
+        if tag == own_tag {
+            return Some(String::from(rest_track_name));
+        }
+        retag_track_name(rest_track_name, &own_tag, tag)
+    }
+
+    /// The names of the animations `_animations_from` would build for the given tag, without building them.
+    fn _animation_names_from(&self, wld: &WldDoc, tag: &str) -> HashSet<String> {
+        let all_tracks: Vec<&Track> = wld.fragment_iter::<Track>().collect();
+        self.get_frag()
+            .dags
+            .iter()
+            .filter_map(|dag| self._rest_track_name(dag, tag))
+            .flat_map(|rest_track_name| {
+                bone_tracks(wld, &all_tracks, &rest_track_name)
+                    .into_iter()
+                    .map(|(animation_name, _)| animation_name)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Builds the animations of this skeleton from the tracks of the given actor tag, in the given WLD.
+    /// Tracks are matched to bones by name, so the tag's skeleton must use the same bone names as this one.
+    /// Only the animations whose names pass `keep` are built.
+    fn _animations_from(&self, wld: &WldDoc, tag: &str, keep: impl Fn(&str) -> bool) -> HashMap<String, Gd<Animation>> {
         let frag = self.get_frag();
 
         let mut animations: HashMap<String, Gd<Animation>> = HashMap::new();
@@ -295,85 +652,71 @@ impl S3DHierSprite {
         // It would be better if this could be cached on the whole wld.
 
         let all_tracks: Vec<&Track> = wld.fragment_iter::<Track>().collect();
+        let bone_names = self._bone_names();
 
-        for dag in &frag.dags {
-            let rest_track = self.get_dag_rest_track(&dag);
-            let rest_track_name = wld.get_string(rest_track.name_reference).unwrap();
-            let dag_name = wld
-                .get_string(StringReference::new(dag.name_reference))
-                .expect("Dag should have a name");
-            let bone_name = bone_name_from_dag(&actor_tag, dag_name);
-
-            let matching_tracks: Vec<&&Track> = all_tracks
-                .iter()
-                .filter_map(|track| {
-                    let trackdef_name = wld
-                        .get_string(track.name_reference)
-                        .expect("TRACKs should have names");
-                    if trackdef_name.ends_with(rest_track_name) {
-                        Some(track)
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            for dag_track in matching_tracks {
-                let dag_track_name = wld.get_string(dag_track.name_reference).unwrap();
+        for (dag, bone_name) in frag.dags.iter().zip(bone_names) {
+            let Some(rest_track_name) = self._rest_track_name(dag, tag) else {
+                continue;
+            };
 
-                //let dag_trackdef_name = wld.get_string(dag_trackdef.name_reference).unwrap();
-                let mut animation_name = dag_track_name.replace(rest_track_name, "");
-                if animation_name == "" {
-                    animation_name = String::from(REST_ANIMATION_NAME);
+            for (animation_name, dag_track) in bone_tracks(wld, &all_tracks, &rest_track_name) {
+                if !keep(&animation_name) {
+                    continue;
                 }
                 if !animations.contains_key(&animation_name) {
                     let mut anim = Animation::new_gd();
-                    // Note, if 'interpolate' is false this seems to mean there is no animation (just a single frame)
-                    // I don't see what use to make of this, however.
-                    // if !(dag_track.flags.interpolate()) {
-                    //     //godot_error!("Track no interpolate: {}", dag_track_name);
-                    // }
                     anim.set_length(0.); // Default length is 1 second.  Set to 0, as we calculate length later.
-                    //anim.set_loop_mode(LoopMode::LINEAR); // FIXME: Playback mode may be in fragment.  Defaulting to loopingw.
+                    if self.animation_names.is_looping(&animation_name) {
+                        anim.set_loop_mode(LoopMode::LINEAR);
+                    }
                     animations.insert(animation_name.clone(), anim);
                 }
                 let anim = animations.get_mut(&animation_name).unwrap();
                 let pos_track_idx = anim.add_track(TrackType::POSITION_3D);
                 let rot_track_idx = anim.add_track(TrackType::ROTATION_3D);
 
+                let playback = TrackPlayback::from_track(dag_track);
+
                 let bone_path = NodePath::from(format!("{0}:{1}", skeleton_path, bone_name));
                 anim.track_set_path(pos_track_idx, &bone_path);
-                // This is the default
-                // anim.track_set_interpolation_type(
-                //     pos_track_idx,
-                //     InterpolationType::INTERPOLATION_LINEAR,
-                // );
                 anim.track_set_path(rot_track_idx, &bone_path);
-                anim.track_set_interpolation_type(
-                    rot_track_idx,
-                    InterpolationType::LINEAR_ANGLE, // Linear interpolation with shortest path rotation.  This seems to match EQ better, but there are problems.
-                );
+                if playback.interpolate {
+                    // Linear is the default for the position track.
+                    anim.track_set_interpolation_type(
+                        rot_track_idx,
+                        InterpolationType::LINEAR_ANGLE, // Linear interpolation with shortest path rotation.  This seems to match EQ better, but there are problems.
+                    );
+                } else {
+                    anim.track_set_interpolation_type(pos_track_idx, InterpolationType::NEAREST);
+                    anim.track_set_interpolation_type(rot_track_idx, InterpolationType::NEAREST);
+                }
 
                 let dag_trackdef = wld
                     .get(&dag_track.reference)
                     .expect("TRACK should reference TRACKDEF");
 
-                let sleep = dag_track.sleep.unwrap_or(100); // 100 ms is the default - sometimes this is explicit, sometimes it is not.
-                let secs_per_frame: f64 = sleep as f64 * 0.001;
+                let mut frames: Vec<(Quaternion, Vector3)> = (0..dag_trackdef.frame_count)
+                    .map(|frame_index| parse_frame_transform(&dag_trackdef, frame_index as usize))
+                    .collect();
+                if playback.reverse {
+                    frames.reverse();
+                }
 
-                for frame_index in 0..dag_trackdef.frame_count {
-                    let (rotation, position) =
-                        parse_frame_transform(&dag_trackdef, frame_index as usize);
-                    let frame_secs = frame_index as f64 * secs_per_frame;
-                    anim.position_track_insert_key(pos_track_idx, frame_secs, position);
+                // Many tracks hold a bone still for the whole animation.  These only need a single key.
+                let rotations: Vec<Quaternion> = frames.iter().map(|frame| frame.0).collect();
+                for (frame_secs, rotation) in reduced_keys(&rotations, playback.secs_per_frame) {
                     anim.rotation_track_insert_key(rot_track_idx, frame_secs, rotation);
                 }
+                let positions: Vec<Vector3> = frames.iter().map(|frame| frame.1).collect();
+                for (frame_secs, position) in reduced_keys(&positions, playback.secs_per_frame) {
+                    anim.position_track_insert_key(pos_track_idx, frame_secs, position);
+                }
 
                 // NOTE: Some tracks of the animation will be shorter than others, or have only a single keyframe.
                 // For this reason we cannot set the duration on the first DAG we find, but rather make sure it's as long
                 // As the longest DAG animation.
 
-                let duration = dag_trackdef.frame_count as f32 * secs_per_frame as f32;
+                let duration = dag_trackdef.frame_count as f32 * playback.secs_per_frame as f32;
 
                 if anim.get_length() < duration {
                     anim.set_length(duration);
@@ -384,6 +727,141 @@ impl S3DHierSprite {
         animations
     }
 
+    /// The animations of this skeleton, followed by those it borrows by following `tag_rules` from its tag.
+    /// The first of `sources` that has tracks for a tag is used, and animations found earlier take precedence.
+    fn _shared_animations(&self, sources: &[Arc<WldDoc>], tag_rules: &Dictionary) -> HashMap<String, Gd<Animation>> {
+        let mut animations = self._animations();
+        let mut tag = self._tag();
+        let mut visited = vec![tag.clone()];
+        while let Some(next_tag) = tag_rules
+            .get(GString::from(&tag))
+            .and_then(|tag| tag.try_to::<GString>().ok())
+            .map(|tag| tag.to_string())
+        {
+            // Stop at rules that lead back to a tag we have already used.
+            if visited.contains(&next_tag) {
+                break;
+            }
+            // Only the animations this skeleton doesn't have yet are built from the source.
+            let borrowed = sources
+                .iter()
+                .find(|wld| !self._animation_names_from(wld, &next_tag).is_empty())
+                .map(|wld| {
+                    self._animations_from(wld, &next_tag, |animation_name| {
+                        animation_name != REST_ANIMATION_NAME && !animations.contains_key(animation_name)
+                    })
+                })
+                .unwrap_or_default();
+            animations.extend(borrowed);
+            visited.push(next_tag.clone());
+            tag = next_tag;
+        }
+        animations
+    }
+
+    /// The given animations under their raw names, plus their aliases.
+    /// If two animations have the same alias (or an alias is also a raw name), a warning is printed and only the first
+    /// by raw name gets the alias, so no animation is silently replaced.
+    fn _named_animations(&self, animations: HashMap<String, Gd<Animation>>) -> Vec<(String, Gd<Animation>)> {
+        let mut animations: Vec<(String, Gd<Animation>)> = animations.into_iter().collect();
+        animations.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut used_names: HashMap<String, String> = animations
+            .iter()
+            .map(|(animation_name, _)| (animation_name.clone(), animation_name.clone()))
+            .collect();
+        let aliases: Vec<(String, Gd<Animation>)> = animations
+            .iter()
+            .filter_map(|(animation_name, animation)| {
+                let alias = self.animation_names.alias(animation_name)?;
+                if let Some(other_name) = used_names.get(&alias) {
+                    godot_warn!(
+                        "Animations {other_name} and {animation_name} both have the name \"{alias}\" - {animation_name} is only available under its raw name"
+                    );
+                    return None;
+                }
+                used_names.insert(alias.clone(), animation_name.clone());
+                Some((alias, animation.clone()))
+            })
+            .collect();
+        animations.extend(aliases);
+        animations
+    }
+
+    fn _bone_names(&self) -> Vec<String> {
+        let actor_tag = self._tag();
+        let wld = self.get_wld();
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        self.get_frag()
+            .dags
+            .iter()
+            .map(|dag| {
+                let dag_name = wld
+                    .get_string(StringReference::new(dag.name_reference))
+                    .expect("Dag should have a name");
+                let bone_name = bone_name_from_dag(&actor_tag, dag_name);
+                let count = counts.entry(bone_name.clone()).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    bone_name
+                } else {
+                    format!("{bone_name}_{count}")
+                }
+            })
+            .collect()
+    }
+
+    fn _rest_transforms(&self) -> Vec<Transform3D> {
+        let wld = self.get_wld();
+        self.get_frag()
+            .dags
+            .iter()
+            .map(|dag| {
+                let track = self.get_dag_rest_track(dag);
+                match wld.get(&track.reference) {
+                    Some(trackdef) => {
+                        let (rotation, position) = parse_frame_transform(trackdef, 0);
+                        Transform3D::new(Basis::from_quat(rotation), position)
+                    }
+                    None => Transform3D::IDENTITY,
+                }
+            })
+            .collect()
+    }
+
+    /// The parent of each DAG, or -1 for the root.
+    fn _parent_indices(&self) -> Vec<i32> {
+        let dags = &self.get_frag().dags;
+        let mut parents = vec![-1; dags.len()];
+        for (index, dag) in dags.iter().enumerate() {
+            for sub_dag in &dag.sub_dags {
+                if let Some(parent) = parents.get_mut(*sub_dag as usize) {
+                    *parent = index as i32;
+                }
+            }
+        }
+        parents
+    }
+
+    fn _global_rest_transforms(&self) -> Vec<Transform3D> {
+        let local = self._rest_transforms();
+        let parents = self._parent_indices();
+        (0..local.len())
+            .map(|index| {
+                let mut transform = local[index];
+                let mut parent = parents[index];
+                // A valid hierarchy can't be deeper than the number of bones - this guards against cycles.
+                for _ in 0..local.len() {
+                    if parent < 0 {
+                        break;
+                    }
+                    transform = local[parent as usize] * transform;
+                    parent = parents[parent as usize];
+                }
+                transform
+            })
+            .collect()
+    }
+
     fn _tag(&self) -> String {
         self._name().replace("_HS_DEF", "")
     }
@@ -408,6 +886,7 @@ impl S3DHierSprite {
 
     /// Each DAG will reference the animation track for the rest-pose animation.
     /// Return that trackdef
+    /// The track a DAG uses at rest.  Every DAG has one - `load` rejects skeletons where it is missing.
     fn get_dag_rest_track(&self, dag: &Dag) -> &Track {
         let wld = self.get_wld();
         wld.get(&FragmentRef::<Track>::new(dag.track_reference as i32))
@@ -417,9 +896,11 @@ impl S3DHierSprite {
     pub fn from_reference(wld: &Arc<WldDoc>, reference: &HierarchicalSprite) -> Option<Gd<Self>> {
         match reference.reference {
             FragmentRef::Index(index, _) => {
-                let fragment = wld.at(index as usize - 1).unwrap();
+                let fragment = wld.at((index as usize).checked_sub(1)?)?;
                 match fragment {
-                    FragmentType::HierarchicalSpriteDef(_) => Some(gd_from_frag_type::<Self>(wld, index)),
+                    FragmentType::HierarchicalSpriteDef(_) => gd_from_frag_type::<Self>(wld, index)
+                        .map_err(|e| godot_error!("{e}"))
+                        .ok(),
                     _ => None,
                 }
             }
@@ -439,6 +920,19 @@ fn bone_name_from_dag(actor_tag: &str, dag_name: &str) -> String {
     bone_name
 }
 
+/// Returns the time and value of each key for a track with the given frames.
+/// If every frame is the same, only the first is returned.
+fn reduced_keys<T: ApproxEq + Copy>(frames: &[T], secs_per_frame: f64) -> Vec<(f64, T)> {
+    match frames.first() {
+        Some(first) if frames.iter().all(|frame| frame.approx_eq(first)) => vec![(0., *first)],
+        _ => frames
+            .iter()
+            .enumerate()
+            .map(|(frame_index, frame)| (frame_index as f64 * secs_per_frame, *frame))
+            .collect(),
+    }
+}
+
 fn frame_position(transform: &FrameTransform) -> Vector3 {
     if transform.shift_denominator == 0 {
         return Vector3::ZERO;
@@ -482,3 +976,54 @@ fn legacy_frame_quaternion(transform: &LegacyFrameTransform) -> Quaternion {
     )
     .normalized()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bone_names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    #[test]
+    fn finds_attachment_bones_without_case() {
+        let names = bone_names(&["ROOT", "BI_L", "r_point", "SHIELD_POINT"]);
+        assert_eq!(find_attachment_bone(&names, "R_POINT"), Some(2));
+        assert_eq!(find_attachment_bone(&names, "shield_point"), Some(3));
+        assert_eq!(find_attachment_bone(&names, "L_POINT"), None);
+    }
+
+    #[test]
+    fn finds_attachment_bones_with_another_tag() {
+        let names = bone_names(&["ROOT", "ELFR_POINT", "HEAD_POINT"]);
+        assert_eq!(find_attachment_bone(&names, "R_POINT"), Some(1));
+        assert_eq!(find_attachment_bone(&names, "HEAD_POINT"), Some(2));
+    }
+
+    #[test]
+    fn prefers_exact_attachment_bones() {
+        let names = bone_names(&["ELFR_POINT", "R_POINT"]);
+        assert_eq!(find_attachment_bone(&names, "R_POINT"), Some(1));
+        // SHIELD_POINT ends with D_POINT, but SHIEL is not a tag.
+        assert_eq!(find_attachment_bone(&bone_names(&["SHIELD_POINT"]), "D_POINT"), None);
+    }
+
+    #[test]
+    fn retags_the_tag_prefix() {
+        assert_eq!(retag_track_name("HUM_BL_R_TRACK", "HUM", "ELF"), Some(String::from("ELF_BL_R_TRACK")));
+    }
+
+    #[test]
+    fn retag_leaves_the_tag_elsewhere_in_the_name() {
+        // Only the leading tag is replaced, even if the tag also appears later in the name.
+        assert_eq!(
+            retag_track_name("ELF_ELFHEAD_TRACK", "ELF", "HUM"),
+            Some(String::from("HUM_ELFHEAD_TRACK"))
+        );
+    }
+
+    #[test]
+    fn retag_rejects_names_without_the_tag_prefix() {
+        assert_eq!(retag_track_name("BL_HUM_TRACK", "HUM", "ELF"), None);
+    }
+}
@@ -0,0 +1,516 @@
+use godot::classes::animation::{LoopMode, TrackType, UpdateMode};
+use godot::classes::light_3d::Param;
+use godot::classes::{Animation, OmniLight3D, RefCounted};
+use godot::prelude::*;
+use libeq_wld::parser::{
+    AmbientLight, DirectionalLight, FragmentRef, GlobalAmbientLightDef, Light, LightDef, PointLight,
+    WldDoc,
+};
+use std::sync::Arc;
+extern crate owning_ref;
+use crate::error::LoadError;
+use crate::wld::gd_from_frag_type;
+use super::{create_fragment_ref, S3DFragment};
+use crate::util::{u32_to_color, wld_f32_pos_to_gd, WORLD_SCALE};
+use owning_ref::ArcRef;
+#[cfg(feature = "serde")]
+use super::frag_to_dict;
+
+/// `POINTLIGHT` flags
+const POINTLIGHT_FLAG_STATIC: u32 = 0x20;
+const POINTLIGHT_FLAG_STATIC_INFLUENCE: u32 = 0x40;
+const POINTLIGHT_FLAG_HAS_REGIONS: u32 = 0x80;
+
+/// Creates an S3DLightDef from a reference to the `LIGHT` fragment that references it.
+fn light_def_from_reference(wld: &Arc<WldDoc>, reference: &FragmentRef<Light>) -> Option<Gd<S3DLightDef>> {
+    S3DLightDef::from_reference(wld, wld.get(reference)?)
+}
+
+/// Returns the frames of the `LIGHTDEFINITION` referenced by a `LIGHT` fragment.
+/// If the reference is invalid, there is a single white frame.
+fn light_frames(wld: &WldDoc, reference: &FragmentRef<Light>) -> Vec<(Color, f32)> {
+    wld.get(reference)
+        .and_then(|light| wld.get(&light.reference))
+        .map(light_def_frames)
+        .unwrap_or_else(|| vec![(Color::WHITE, 1.)])
+}
+
+/// The first color of the `LIGHTDEFINITION` referenced by a `LIGHT` fragment, or white.
+fn light_color(wld: &WldDoc, reference: &FragmentRef<Light>) -> Color {
+    light_frames(wld, reference)[0].0
+}
+
+/// The color of an ambient light.
+pub fn ambient_light_color(wld: &WldDoc, light: &AmbientLight) -> Color {
+    light_color(wld, &light.reference)
+}
+
+/// The color of the global ambient light of a zone.
+pub fn global_ambient_light_color(light: &GlobalAmbientLightDef) -> Color {
+    u32_to_color(&light.color)
+}
+
+/// Returns the color and light level of each frame of a light definition.
+/// There is always at least one frame.  Lights without colors are white, and lights without levels have a level of 1.
+fn light_def_frames(light_def: &LightDef) -> Vec<(Color, f32)> {
+    let colors = light_def.colors.as_deref().unwrap_or(&[]);
+    let levels = light_def.light_levels.as_deref().unwrap_or(&[]);
+    let frame_count = colors.len().max(levels.len()).max(1);
+    (0..frame_count)
+        .map(|i| {
+            let color = colors
+                .get(i)
+                .map(|&(r, g, b)| Color::from_rgb(r, g, b))
+                .unwrap_or(Color::WHITE);
+            let level = levels.get(i).copied().unwrap_or(1.);
+            (color, level)
+        })
+        .collect()
+}
+
+/// A wrapper around `LIGHTDEFINITION`, which defines the color of a light.
+/// A light definition with more than one frame flickers, cycling through the frames.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DLightDef {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, LightDef>>,
+}
+
+impl S3DFragment for S3DLightDef {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DLightDef {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    /// The number of frames.  Lights with more than one frame flicker.
+    #[func]
+    pub fn frame_count(&self) -> i32 {
+        light_def_frames(self.get_frag()).len() as i32
+    }
+
+    /// The color of each frame.
+    #[func]
+    pub fn colors(&self) -> PackedColorArray {
+        light_def_frames(self.get_frag())
+            .iter()
+            .map(|(color, _)| *color)
+            .collect()
+    }
+
+    /// The brightness of each frame, from 0 to 1.
+    #[func]
+    pub fn light_levels(&self) -> PackedFloat32Array {
+        light_def_frames(self.get_frag())
+            .iter()
+            .map(|(_, level)| *level)
+            .collect()
+    }
+
+    /// The color of the first frame.
+    #[func]
+    pub fn color(&self) -> Color {
+        light_def_frames(self.get_frag())[0].0
+    }
+
+    /// The frame the light starts on.
+    #[func]
+    pub fn current_frame(&self) -> u32 {
+        self.get_frag().current_frame.unwrap_or(0)
+    }
+
+    /// The delay between each frame in seconds, or 0 if the light does not flicker.
+    #[func]
+    pub fn delay(&self) -> f32 {
+        match self.get_frag().sleep {
+            Some(sleep) => sleep as f32 * 0.001,
+            None => 0.,
+        }
+    }
+
+    /// Returns true if frames are skipped rather than cycled in order.
+    #[func]
+    pub fn skip_frames(&self) -> bool {
+        self.get_frag().flags.skip_frames()
+    }
+
+    /// Returns a looping Animation that cycles the `light_color` and `light_energy` of a light through the frames.
+    /// `light_path` is the path of the Light3D node, relative to the root node of the AnimationPlayer.
+    /// Returns null if the light does not flicker.
+    #[func]
+    pub fn flicker_animation(&self, light_path: NodePath) -> Option<Gd<Animation>> {
+        let frames = light_def_frames(self.get_frag());
+        let delay = self.delay();
+        if frames.len() < 2 || delay <= 0. {
+            return None;
+        }
+        let mut animation = Animation::new_gd();
+        animation.set_length(delay * frames.len() as f32);
+        animation.set_loop_mode(LoopMode::LINEAR);
+        let path = light_path.to_string();
+        let color_track = animation.add_track(TrackType::VALUE);
+        animation.track_set_path(color_track, &NodePath::from(format!("{path}:light_color")));
+        animation.value_track_set_update_mode(color_track, UpdateMode::DISCRETE);
+        let energy_track = animation.add_track(TrackType::VALUE);
+        animation.track_set_path(energy_track, &NodePath::from(format!("{path}:light_energy")));
+        animation.value_track_set_update_mode(energy_track, UpdateMode::DISCRETE);
+        for (i, (color, level)) in frames.iter().enumerate() {
+            let time = (i as f32 * delay) as f64;
+            animation.track_insert_key(color_track, time, &color.to_variant());
+            animation.track_insert_key(energy_track, time, &level.to_variant());
+        }
+        Some(animation)
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DLightDef {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &LightDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    /// Creates an S3DLightDef from a `LIGHT` fragment, which only references the light definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, light: &Light) -> Option<Gd<Self>> {
+        match light.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DLightDef>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
+
+/// A wrapper around `POINTLIGHT`, which places a light in a zone.  These are found in the lights.wld of a zone archive.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DLight {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, PointLight>>,
+}
+
+impl S3DFragment for S3DLight {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DLight {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The position of the light, in Godot coordinates.
+    #[func]
+    pub fn position(&self) -> Vector3 {
+        let frag = self.get_frag();
+        wld_f32_pos_to_gd(&(frag.x, frag.y, frag.z))
+    }
+
+    /// The distance the light reaches, in Godot units.
+    #[func]
+    pub fn radius(&self) -> f32 {
+        self.get_frag().radius * WORLD_SCALE
+    }
+
+    /// Returns true if the light is static, i.e. its lighting is already baked into the vertex colors of the zone.
+    #[func]
+    pub fn is_static(&self) -> bool {
+        self.get_frag().flags & POINTLIGHT_FLAG_STATIC != 0
+    }
+
+    /// Returns true if the light only affects static geometry.
+    #[func]
+    pub fn has_static_influence(&self) -> bool {
+        self.get_frag().flags & POINTLIGHT_FLAG_STATIC_INFLUENCE != 0
+    }
+
+    /// Returns true if the light is limited to a list of regions.
+    #[func]
+    pub fn has_regions(&self) -> bool {
+        self.get_frag().flags & POINTLIGHT_FLAG_HAS_REGIONS != 0
+    }
+
+    /// The light definition, which has the color and flicker frames of the light.
+    #[func]
+    pub fn light_def(&self) -> Option<Gd<S3DLightDef>> {
+        light_def_from_reference(self.get_wld(), &self.get_frag().reference)
+    }
+
+    /// The color of the first frame of the light definition.
+    #[func]
+    pub fn color(&self) -> Color {
+        light_color(self.get_wld(), &self.get_frag().reference)
+    }
+
+    /// Creates an OmniLight3D with the position, range, color and brightness of the light.
+    /// For flickering lights, see `S3DLightDef.flicker_animation`.
+    #[func]
+    pub fn to_omni_light(&self) -> Gd<OmniLight3D> {
+        let (color, level) = light_frames(self.get_wld(), &self.get_frag().reference)[0];
+        let mut light = OmniLight3D::new_alloc();
+        light.set_name(&self.name());
+        light.set_position(self.position());
+        light.set_param(Param::RANGE, self.radius());
+        light.set_param(Param::ENERGY, level);
+        light.set_color(color);
+        light
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DLight {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &PointLight {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+}
+
+/// A wrapper around `AMBIENTLIGHT`, which lights a list of regions of a zone.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DAmbientLight {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, AmbientLight>>,
+}
+
+impl S3DFragment for S3DAmbientLight {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DAmbientLight {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The regions lit by this light.  These start at 0, like the regions of `S3DZoneRegions`.
+    #[func]
+    pub fn regions(&self) -> PackedInt32Array {
+        self.get_frag().regions.iter().map(|&region| region as i32).collect()
+    }
+
+    /// The light definition, which has the color and flicker frames of the light.
+    #[func]
+    pub fn light_def(&self) -> Option<Gd<S3DLightDef>> {
+        light_def_from_reference(self.get_wld(), &self.get_frag().reference)
+    }
+
+    /// The color of the first frame of the light definition.
+    #[func]
+    pub fn color(&self) -> Color {
+        ambient_light_color(self.get_wld(), self.get_frag())
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DAmbientLight {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &AmbientLight {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+}
+
+/// A wrapper around `GLOBALAMBIENTLIGHTDEF`, which is the ambient light of a whole zone.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DGlobalAmbientLight {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, GlobalAmbientLightDef>>,
+}
+
+impl S3DFragment for S3DGlobalAmbientLight {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DGlobalAmbientLight {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn color(&self) -> Color {
+        global_ambient_light_color(self.get_frag())
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DGlobalAmbientLight {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &GlobalAmbientLightDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+}
+
+/// A wrapper around `DIRECTIONALLIGHT`.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DDirectionalLight {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, DirectionalLight>>,
+}
+
+impl S3DFragment for S3DDirectionalLight {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DDirectionalLight {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The light definition, which has the color and flicker frames of the light.
+    #[func]
+    pub fn light_def(&self) -> Option<Gd<S3DLightDef>> {
+        light_def_from_reference(self.get_wld(), &self.get_frag().reference)
+    }
+
+    /// The color of the first frame of the light definition.
+    #[func]
+    pub fn color(&self) -> Color {
+        light_color(self.get_wld(), &self.get_frag().reference)
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DDirectionalLight {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &DirectionalLight {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+}
@@ -1,48 +1,132 @@
-use godot::classes::RefCounted;
+use godot::classes::base_material_3d::{
+    BlendMode as BaseBlendMode, CullMode, Flags, ShadingMode, TextureFilter, TextureParam,
+    Transparency,
+};
+use godot::classes::{
+    Material, RefCounted, ShaderMaterial, StandardMaterial3D, Texture2D, Texture2DArray,
+};
 use godot::prelude::*;
 use libeq_wld::parser::{MaterialDef, RenderMethod, SimpleSpriteDef, WldDoc};
 use std::sync::Arc;
 extern crate owning_ref;
-use super::{create_fragment_ref, S3DFragment};
+use crate::error::LoadError;
+use super::{create_fragment_ref, iter_simple_sprite_texture_filenames, S3DFragment};
 use owning_ref::ArcRef;
 #[cfg(feature = "serde")]
 use super::frag_to_dict;
-// FIXME: Enums are not yet supported in gdext rust.  For now just handle in GDScript
+/// The blend mode a material is rendered with.
+/// These values are exposed to GDScript as the `BLEND_MODE_*` constants on `S3DMaterial`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Opaque = 0,
+    Alpha = 1,
+    Additive = 2,
+}
+
+/// The decoded render method of a material.
+/// These values are exposed to GDScript as the `MATERIAL_TYPE_*` constants on `S3DMaterial`.
 /// Source: LanternExtractor
 /// (https://github.com/LanternEQ/LanternExtractor/blob/afe174b71ac9f9ab75e259bac2282735b093426d/LanternExtractor/EQ/Wld/DataTypes/MaterialType.cs)
-// pub enum MaterialType {
-//     /// Used for boundaries that are not rendered. TextInfoReference can be null or have reference.
-//     Boundary = 0x0,
-//     /// Standard diffuse shader
-//     Diffuse = 0x01,
-//     /// Diffuse variant
-//     Diffuse2 = 0x02,
-//     //// Transparent with 0.5 blend strength
-//     Transparent50 = 0x05,
-//     /// Transparent with 0.25 blend strength
-//     Transparent25 = 0x09,
-//     /// Transparent with 0.75 blend strength
-//     Transparent75 = 0x0A,
-//     /// Non solid surfaces that shouldn't really be masked
-//     TransparentMaskedPassable = 0x07,
-//     TransparentAdditiveUnlit = 0x0B,
-//     TransparentMasked = 0x13,
-//     Diffuse3 = 0x14,
-//     Diffuse4 = 0x15,
-//     TransparentAdditive = 0x17,
-//     Diffuse5 = 0x19,
-//     InvisibleUnknown = 0x53,
-//     Diffuse6 = 0x553,
-//     CompleteUnknown = 0x1A, // TODO: Analyze this
-//     Diffuse7 = 0x12,
-//     Diffuse8 = 0x31,
-//     InvisibleUnknown2 = 0x4B,
-//     DiffuseSkydome = 0x0D,     // Need to confirm
-//     TransparentSkydome = 0x0F, // Need to confirm
-//     TransparentAdditiveUnlitSkydome = 0x10,
-//     InvisibleUnknown3 = 0x03,
-//     CompleteUnknown2 = 0x06, // Found on a "floor" wall in tanarus 'thecity'
-// }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaterialType {
+    /// Used for boundaries that are not rendered.
+    Boundary = 0,
+    /// Standard diffuse shader
+    Diffuse = 1,
+    /// Transparent with 0.25 blend strength
+    Transparent25 = 2,
+    /// Transparent with 0.5 blend strength
+    Transparent50 = 3,
+    /// Transparent with 0.75 blend strength
+    Transparent75 = 4,
+    /// Cutout transparency, using the key color of the texture.  Used for trees, fences etc.
+    TransparentMasked = 5,
+    /// Non solid surfaces that shouldn't really be masked
+    TransparentMaskedPassable = 6,
+    TransparentAdditive = 7,
+    TransparentAdditiveUnlit = 8,
+    DiffuseSkydome = 9,
+    TransparentSkydome = 10,
+    TransparentAdditiveUnlitSkydome = 11,
+    /// Not rendered.  Polygons using this material only have collision.
+    Invisible = 12,
+}
+
+impl MaterialType {
+    /// Decodes the user-defined material type id stored in the render method
+    pub fn from_id(id: u32) -> Self {
+        match id {
+            0x00 => MaterialType::Boundary,
+            0x05 => MaterialType::Transparent50,
+            0x09 => MaterialType::Transparent25,
+            0x0A => MaterialType::Transparent75,
+            0x07 => MaterialType::TransparentMaskedPassable,
+            0x0B => MaterialType::TransparentAdditiveUnlit,
+            0x13 => MaterialType::TransparentMasked,
+            0x17 => MaterialType::TransparentAdditive,
+            0x0D => MaterialType::DiffuseSkydome,
+            0x0F => MaterialType::TransparentSkydome,
+            0x10 => MaterialType::TransparentAdditiveUnlitSkydome,
+            0x03 | 0x4B | 0x53 => MaterialType::Invisible,
+            // 0x01, 0x02, 0x12, 0x14, 0x15, 0x19, 0x31 and 0x553 are all diffuse variants.
+            // 0x06 and 0x1A are unknown, and are treated as diffuse.
+            _ => MaterialType::Diffuse,
+        }
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        match self {
+            MaterialType::Transparent25
+            | MaterialType::Transparent50
+            | MaterialType::Transparent75
+            | MaterialType::TransparentSkydome => BlendMode::Alpha,
+            MaterialType::TransparentAdditive
+            | MaterialType::TransparentAdditiveUnlit
+            | MaterialType::TransparentAdditiveUnlitSkydome => BlendMode::Additive,
+            _ => BlendMode::Opaque,
+        }
+    }
+
+    pub fn opacity(&self) -> f32 {
+        match self {
+            MaterialType::Transparent25 => 0.25,
+            MaterialType::Transparent50 | MaterialType::TransparentSkydome => 0.5,
+            MaterialType::Transparent75 => 0.75,
+            _ => 1.,
+        }
+    }
+
+    pub fn is_masked(&self) -> bool {
+        matches!(
+            self,
+            MaterialType::TransparentMasked | MaterialType::TransparentMaskedPassable
+        )
+    }
+
+    pub fn is_unlit(&self) -> bool {
+        matches!(
+            self,
+            MaterialType::TransparentAdditiveUnlit
+                | MaterialType::DiffuseSkydome
+                | MaterialType::TransparentSkydome
+                | MaterialType::TransparentAdditiveUnlitSkydome
+        )
+    }
+
+    pub fn is_visible(&self) -> bool {
+        !matches!(self, MaterialType::Boundary | MaterialType::Invisible)
+    }
+}
+
+/// Decodes the render method of a material fragment.
+pub fn decode_material_type(material: &MaterialDef) -> MaterialType {
+    match material.render_method {
+        RenderMethod::UserDefined { material_type } => MaterialType::from_id(material_type as u32),
+        // If the render method is 0, this is an invisible material.
+        ref render_method if render_method.as_u32() == 0 => MaterialType::Boundary,
+        _ => MaterialType::Diffuse,
+    }
+}
 
 #[derive(GodotClass)]
 #[class(init)]
@@ -52,8 +136,9 @@ pub struct S3DMaterial {
 }
 
 impl S3DFragment for S3DMaterial {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) {
-        self.fragment = Some(create_fragment_ref(wld.clone(), index));
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
     }
 }
 
@@ -77,7 +162,7 @@ impl S3DMaterial {
     /// Returns true if the material is visible.  Invisible materials refer to polygons that have collision but are invisible.
     #[func]
     pub fn visible(&self) -> bool {
-        return self.get_frag().render_method.as_u32() != 0;
+        self.get_material_type().is_visible()
     }
 
     /// Returns the index number of the correct shader for this material.
@@ -90,9 +175,142 @@ impl S3DMaterial {
         }
     }
 
+    #[constant]
+    const BLEND_MODE_OPAQUE: i32 = BlendMode::Opaque as i32;
+    #[constant]
+    const BLEND_MODE_ALPHA: i32 = BlendMode::Alpha as i32;
+    #[constant]
+    const BLEND_MODE_ADDITIVE: i32 = BlendMode::Additive as i32;
+
+    #[constant]
+    const MATERIAL_TYPE_BOUNDARY: i32 = MaterialType::Boundary as i32;
+    #[constant]
+    const MATERIAL_TYPE_DIFFUSE: i32 = MaterialType::Diffuse as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_25: i32 = MaterialType::Transparent25 as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_50: i32 = MaterialType::Transparent50 as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_75: i32 = MaterialType::Transparent75 as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_MASKED: i32 = MaterialType::TransparentMasked as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_MASKED_PASSABLE: i32 = MaterialType::TransparentMaskedPassable as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_ADDITIVE: i32 = MaterialType::TransparentAdditive as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_ADDITIVE_UNLIT: i32 = MaterialType::TransparentAdditiveUnlit as i32;
+    #[constant]
+    const MATERIAL_TYPE_DIFFUSE_SKYDOME: i32 = MaterialType::DiffuseSkydome as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_SKYDOME: i32 = MaterialType::TransparentSkydome as i32;
+    #[constant]
+    const MATERIAL_TYPE_TRANSPARENT_ADDITIVE_UNLIT_SKYDOME: i32 = MaterialType::TransparentAdditiveUnlitSkydome as i32;
+    #[constant]
+    const MATERIAL_TYPE_INVISIBLE: i32 = MaterialType::Invisible as i32;
+
+    /// Returns the decoded render method, as one of the `MATERIAL_TYPE_*` constants.
+    #[func]
+    pub fn material_type(&self) -> i32 {
+        self.get_material_type() as i32
+    }
+
+    /// Returns how the material is blended, as one of the `BLEND_MODE_*` constants.
+    #[func]
+    pub fn blend_mode(&self) -> i32 {
+        self.get_material_type().blend_mode() as i32
+    }
+
+    /// Returns the opacity of alpha-blended materials.  Other materials are fully opaque.
+    #[func]
+    pub fn opacity(&self) -> f32 {
+        self.get_material_type().opacity()
+    }
+
+    /// Returns true if the material uses cutout transparency, using the key color of its texture.
+    #[func]
+    pub fn is_masked(&self) -> bool {
+        self.get_material_type().is_masked()
+    }
+
+    /// Returns true if the material is not affected by lighting.
+    #[func]
+    pub fn is_unlit(&self) -> bool {
+        self.get_material_type().is_unlit()
+    }
+
+    /// Builds a StandardMaterial3D configured for this material's blend mode, opacity, cutout and culling.
+    /// `textures` maps texture filenames (see `texture_filename()`) to Texture2Ds.
+    /// Returns null for invisible materials.
+    ///
+    /// Note that cutout transparency uses the alpha channel of the texture, so masked materials
+    /// need textures that have their key color converted to alpha - see `EQTextureOptions.key_color_alpha`.
+    #[func]
+    pub fn to_material(&self, textures: Dictionary) -> Option<Gd<Material>> {
+        if !self.visible() {
+            return None;
+        }
+        let material_type = self.get_material_type();
+        let mut material = StandardMaterial3D::new_gd();
+        material.set_name(&self.name());
+
+        let texture = self
+            .iter_texture_filenames()
+            .next()
+            .and_then(|filename| {
+                let texture = textures.get(filename.clone());
+                if texture.is_none() {
+                    godot_error!("Missing texture: {filename}");
+                }
+                texture
+            })
+            .and_then(|variant| variant.try_to::<Gd<Texture2D>>().ok());
+        if let Some(texture) = texture {
+            // Archive textures carry the filter hint from their EQTextureOptions.  Other textures keep the default filter.
+            let filter_meta = StringName::from("texture_filter");
+            if texture.has_meta(&filter_meta) {
+                if let Ok(filter) = texture.get_meta(&filter_meta).try_to::<TextureFilter>() {
+                    material.set_texture_filter(filter);
+                }
+            }
+            material.set_texture(TextureParam::ALBEDO, &texture);
+        }
+
+        // Zone meshes and actor instances store their baked lighting in vertex colors.
+        material.set_flag(Flags::ALBEDO_FROM_VERTEX_COLOR, true);
+        material.set_cull_mode(CullMode::BACK);
+
+        match material_type.blend_mode() {
+            BlendMode::Opaque => {
+                if material_type.is_masked() {
+                    material.set_transparency(Transparency::ALPHA_SCISSOR);
+                    material.set_alpha_scissor_threshold(0.5);
+                    // Foliage and fences are drawn from both sides.
+                    material.set_cull_mode(CullMode::DISABLED);
+                }
+            }
+            BlendMode::Alpha => {
+                material.set_transparency(Transparency::ALPHA);
+                material.set_albedo(Color::from_rgba(1., 1., 1., material_type.opacity()));
+                material.set_cull_mode(CullMode::DISABLED);
+            }
+            BlendMode::Additive => {
+                material.set_transparency(Transparency::ALPHA);
+                material.set_blend_mode(BaseBlendMode::ADD);
+                material.set_cull_mode(CullMode::DISABLED);
+            }
+        }
+
+        if material_type.is_unlit() {
+            material.set_shading_mode(ShadingMode::UNSHADED);
+        }
+
+        Some(material.upcast())
+    }
+
     /// For animated textures, there will be multiple filenames.
     #[func]
-    fn texture_filenames(&self) -> PackedStringArray {
+    pub fn texture_filenames(&self) -> PackedStringArray {
         self.iter_texture_filenames().collect()
     }
 
@@ -106,13 +324,45 @@ impl S3DMaterial {
 
     /// For animated textures, the delay between each frame in seconds
     #[func]
-    fn delay(&self) -> f32 {
+    pub fn delay(&self) -> f32 {
         match self.get_simple_sprite().sleep {
             Some(sleep) => sleep as f32 * 0.001,
             None => 0.,
         }
     }
 
+    /// The number of texture frames.  Animated materials such as water and lava have more than one.
+    #[func]
+    pub fn frame_count(&self) -> i32 {
+        self.iter_texture_filenames().count() as i32
+    }
+
+    /// Returns true if the material has more than one texture frame.
+    #[func]
+    pub fn is_animated(&self) -> bool {
+        self.frame_count() > 1
+    }
+
+    /// Sets the shader parameters for an animated material, using the frames from `EQArchive.get_texture_array`.
+    /// The shader is expected to declare these uniforms:
+    ///
+    /// ```glsl
+    /// uniform sampler2DArray frames: source_color;
+    /// uniform int frame_count;
+    /// uniform float frame_duration;
+    /// ```
+    ///
+    /// and to sample the layer `int(TIME / frame_duration) % frame_count`.  See `shaders/eq_animated.gdshader` in the example project.
+    #[func]
+    pub fn apply_animation_parameters(&self, mut material: Gd<ShaderMaterial>, frames: Gd<Texture2DArray>) {
+        let frame_count = frames.get_layers();
+        material.set_shader_parameter("frames", &frames.to_variant());
+        material.set_shader_parameter("frame_count", &frame_count.to_variant());
+        // A frame duration of 0 would divide by zero in the shader, so static materials get an arbitrary duration.
+        let delay = if self.delay() > 0. { self.delay() } else { 1. };
+        material.set_shader_parameter("frame_duration", &delay.to_variant());
+    }
+
     #[cfg(feature = "serde")]
     #[func]
     pub fn as_dict(&self) -> Dictionary {
@@ -138,26 +388,13 @@ impl S3DMaterial {
             .expect("Failed to get Fragment reference!")
     }
 
+    fn get_material_type(&self) -> MaterialType {
+        decode_material_type(self.get_frag())
+    }
+
+    /// The texture filename of each frame, the same as for a sprite drawing the same `SIMPLESPRITEDEF`.
     fn iter_texture_filenames(&self) -> impl Iterator<Item = GString> + '_ {
-        let wld = self.get_wld();
-        let simplesprite = self.get_simple_sprite();
-        simplesprite
-            .frame_references
-            .iter()
-            // [TextureFragment]s reference a [TextureImagesFragment]
-            .map(move |r| wld.get(&r))
-            .flat_map(|image| match image {
-                // The [TextureImagesFragment] itself contains a collection of filenames. In
-                // practice this seems to always be just a single filename.
-                Some(i) => i
-                    .entries
-                    .iter()
-                    // These also seem to be stored in all caps. The s3d files however store
-                    // filenames in lowercase. This accounts for that.
-                    .map(|e| GString::from(e.file_name.to_lowercase()))
-                    .collect::<Vec<_>>(),
-                None => vec![],
-            })
+        iter_simple_sprite_texture_filenames(self.get_wld(), self.get_simple_sprite())
     }
 
     fn get_simple_sprite(&self) -> &SimpleSpriteDef {
@@ -1,15 +1,65 @@
-use godot::classes::RefCounted;
+use godot::classes::mesh::{ArrayType, PrimitiveType};
+use godot::classes::{ArrayMesh, ConcavePolygonShape3D, Material, RefCounted};
 use godot::prelude::*;
 use libeq_wld::parser::{DmSprite, DmSpriteDef, DmSpriteDef2, DmTrackDef2, DmTrackDef, FragmentRef, FragmentType, MaterialDef, WldDoc};
 use std::sync::Arc;
 extern crate owning_ref;
-use super::{create_fragment_ref, S3DFragment};
+use super::{create_fragment_ref, decode_material_type, S3DFragment};
+use crate::error::{LoadError, LoadErrorKind};
+use crate::util::collision::CollisionBuilder;
 use crate::util::{u32_to_color, wld_f32_pos_to_gd, wld_i16_pos_to_gd};
 use crate::wld::gd_from_frag_type;
 use owning_ref::ArcRef;
 #[cfg(feature = "serde")]
 use super::frag_to_dict;
 
+/// Looks up the material of a face material group, and its name.
+/// If the index is out of bounds or the material has no name, an error is printed and None is returned, so the surface is skipped.
+fn surface_material<'a>(
+    wld: &WldDoc,
+    materials: &[&'a MaterialDef],
+    material_idx: usize,
+    mesh_name: &GString,
+) -> Option<(&'a MaterialDef, String)> {
+    let Some(material) = materials.get(material_idx).copied() else {
+        godot_error!(
+            "{}",
+            LoadError::new(
+                LoadErrorKind::InvalidFragment,
+                format!(
+                    "Mesh {mesh_name} uses material {material_idx}, but its material list only has {} materials",
+                    materials.len()
+                ),
+            )
+        );
+        return None;
+    };
+    let Some(material_name) = wld.get_string(material.name_reference) else {
+        godot_error!(
+            "{}",
+            LoadError::new(
+                LoadErrorKind::InvalidFragment,
+                format!("Material {material_idx} of mesh {mesh_name} has no name"),
+            )
+        );
+        return None;
+    };
+    Some((material, String::from(material_name)))
+}
+
+/// Adds an ambient light to a baked vertex color: each color channel is the sum of the two, clamped to 1.
+/// This is an approximation - the vertex colors hold the baked light of the zone's light sources, and the ambient light
+/// of the region is added on top so that unlit areas are not black.
+/// The alpha of the vertex color is kept, since it is the transparency of the vertex rather than light.
+fn lit_vertex_color(color: Color, ambient: Color) -> Color {
+    Color::from_rgba(
+        (color.r + ambient.r).min(1.),
+        (color.g + ambient.g).min(1.),
+        (color.b + ambient.b).min(1.),
+        color.a,
+    )
+}
+
 trait MeshProvider {
     fn name(&self) -> GString;
     fn flags(&self) -> u32;
@@ -23,7 +73,18 @@ trait MeshProvider {
     fn uvs(&self) -> PackedVector2Array; 
     fn bone_indices(&self) -> PackedInt32Array;   
     fn bone_weights(&self) -> PackedFloat32Array;
-    fn face_material_groups(&self) -> Array<VariantArray>; 
+    fn surfaces(&self) -> Vec<MeshSurface>;
+    fn face_material_groups(&self) -> Array<VariantArray> {
+        self.surfaces()
+            .into_iter()
+            .map(|surface| {
+                let mut array = VariantArray::new();
+                array.push(&Variant::from(GString::from(surface.material_name)));
+                array.push(&Variant::from(surface.indices));
+                array
+            })
+            .collect()
+    }
     fn indices(&self) -> PackedInt32Array;   
     fn collision_vertices(&self) -> PackedVector3Array;
     fn is_animated(&self) -> bool;
@@ -33,6 +94,14 @@ trait MeshProvider {
     fn as_dict(&self) -> Dictionary;
 }
 
+/// A group of polygons that share a material, which becomes one surface of an ArrayMesh.
+struct MeshSurface {
+    material_name: String,
+    /// False if the material is invisible.  These polygons have collision but are not rendered.
+    visible: bool,
+    indices: PackedInt32Array,
+}
+
 struct DmSprite2Provider {
    fragment: ArcRef<WldDoc, DmSpriteDef2>
 }
@@ -127,33 +196,32 @@ impl MeshProvider for DmSprite2Provider {
     }
 
 
-    fn face_material_groups(&self) -> Array<VariantArray> {
+    fn surfaces(&self) -> Vec<MeshSurface> {
         let wld = self.get_wld();
         let materials = self.materials();
         let mut pos = 0;
         let frag = self.get_frag();
         frag.face_material_groups
             .iter()
-            .enumerate()
-            .filter_map(|(_, (poly_count, ref material_idx))| {
-                let material = materials[*material_idx as usize];
-
+            .filter_map(|(poly_count, ref material_idx)| {
                 let count = *poly_count as usize;
                 let next_pos = pos + count;
                 let batch = pos..next_pos;
                 pos = next_pos;
 
-                // If the material flags are 0, this is an invisible material.
-                // Since we are dealing with collision separately, we can simply omit these polygons as they serve no purpose for rendering.
-                // FIXME: It may be desirable to keep these for debugging purposes.  It would be wise to provide a flag for this.
-                // if material.render_method.as_u32() == 0 {
-                //     return None;
-                // }
-
-                let indices: PackedInt32Array = frag
-                    .faces
-                    .get(batch)
-                    .expect("Tried to get a Face from a Mesh that does not exist!")
+                let (material, material_name) =
+                    surface_material(wld, &materials, *material_idx as usize, &self.name())?;
+                let Some(faces) = frag.faces.get(batch) else {
+                    godot_error!(
+                        "{}",
+                        LoadError::new(
+                            LoadErrorKind::InvalidFragment,
+                            format!("Mesh {} has fewer faces than its face material groups", self.name()),
+                        )
+                    );
+                    return None;
+                };
+                let indices: PackedInt32Array = faces
                     .iter()
                     .flat_map(|face| {
                         vec![
@@ -164,13 +232,11 @@ impl MeshProvider for DmSprite2Provider {
                     })
                     .collect();
 
-                let mut array = VariantArray::new();
-                array.push(&Variant::from(GString::from(
-                    wld.get_string(material.name_reference)
-                        .expect("Material name should be a valid string"),
-                )));
-                array.push(&Variant::from(indices));
-                return Some(array);
+                Some(MeshSurface {
+                    material_name,
+                    visible: decode_material_type(material).is_visible(),
+                    indices,
+                })
             })
             .collect()
     }
@@ -265,17 +331,27 @@ impl DmSprite2Provider {
             .as_ref()
     }
 
+    /// The materials of the mesh's material list.  An error is printed and the list is empty if the list is invalid,
+    /// so that surfaces can't be given the wrong material.
     fn materials(&self) -> Vec<&MaterialDef> {
         let wld = self.get_wld();
-        wld.get(&self.get_frag().material_list_ref)
-            .expect("Invalid material list reference")
-            .fragments
-            .iter()
-            .map(|fragment_ref| {
-                wld.get(fragment_ref)
-                    .expect("Material should exist - it's in the material list")
-            })
-            .collect()
+        let materials = wld.get(&self.get_frag().material_list_ref).and_then(|material_list| {
+            material_list
+                .fragments
+                .iter()
+                .map(|fragment_ref| wld.get(fragment_ref))
+                .collect::<Option<Vec<_>>>()
+        });
+        materials.unwrap_or_else(|| {
+            godot_error!(
+                "{}",
+                LoadError::new(
+                    LoadErrorKind::InvalidFragment,
+                    format!("Mesh {} has an invalid material list", self.name()),
+                )
+            );
+            Vec::new()
+        })
     }
 
     fn get_dmtrackdef(&self) -> Option<&DmTrackDef2> {
@@ -377,7 +453,7 @@ struct DmSpriteProvider {
     }
 
 
-    fn face_material_groups(&self) -> Array<VariantArray> {
+    fn surfaces(&self) -> Vec<MeshSurface> {
         let wld = self.get_wld();
         let materials = self.materials();
         let mut pos = 0;
@@ -386,26 +462,25 @@ struct DmSpriteProvider {
             Some(face_material_groups) => {
                 face_material_groups
             .iter()
-            .enumerate()
-            .filter_map(|(_, (poly_count, ref material_idx))| {
-                let material = materials[*material_idx as usize];
-
+            .filter_map(|(poly_count, ref material_idx)| {
                 let count = *poly_count as usize;
                 let next_pos = pos + count;
                 let batch = pos..next_pos;
                 pos = next_pos;
 
-                // If the material flags are 0, this is an invisible material.
-                // Since we are dealing with collision separately, we can simply omit these polygons as they serve no purpose for rendering.
-                // FIXME: It may be desirable to keep these for debugging purposes.  It would be wise to provide a flag for this.
-                // if material.render_method.as_u32() == 0 {
-                //     return None;
-                // }
-
-                let indices: PackedInt32Array = frag
-                    .faces
-                    .get(batch)
-                    .expect("Tried to get a Face from a Mesh that does not exist!")
+                let (material, material_name) =
+                    surface_material(wld, &materials, *material_idx as usize, &self.name())?;
+                let Some(faces) = frag.faces.get(batch) else {
+                    godot_error!(
+                        "{}",
+                        LoadError::new(
+                            LoadErrorKind::InvalidFragment,
+                            format!("Mesh {} has fewer faces than its face material groups", self.name()),
+                        )
+                    );
+                    return None;
+                };
+                let indices: PackedInt32Array = faces
                     .iter()
                     .flat_map(|face| {
                         vec![
@@ -416,17 +491,15 @@ struct DmSpriteProvider {
                     })
                     .collect();
 
-                let mut array = VariantArray::new();
-                array.push(&Variant::from(GString::from(
-                    wld.get_string(material.name_reference)
-                        .expect("Material name should be a valid string"),
-                )));
-                array.push(&Variant::from(indices));
-                return Some(array);
+                Some(MeshSurface {
+                    material_name,
+                    visible: decode_material_type(material).is_visible(),
+                    indices,
+                })
             })
             .collect()
             }
-            None => Array::new()
+            None => Vec::new()
         }
     }
 
@@ -498,17 +571,27 @@ struct DmSpriteProvider {
             .as_ref()
     }
 
+    /// The materials of the mesh's material list.  An error is printed and the list is empty if the list is invalid,
+    /// so that surfaces can't be given the wrong material.
     fn materials(&self) -> Vec<&MaterialDef> {
         let wld = self.get_wld();
-        wld.get(&self.get_frag().material_list_ref)
-            .expect("Invalid material list reference")
-            .fragments
-            .iter()
-            .map(|fragment_ref| {
-                wld.get(fragment_ref)
-                    .expect("Material should exist - it's in the material list")
-            })
-            .collect()
+        let materials = wld.get(&self.get_frag().material_list_ref).and_then(|material_list| {
+            material_list
+                .fragments
+                .iter()
+                .map(|fragment_ref| wld.get(fragment_ref))
+                .collect::<Option<Vec<_>>>()
+        });
+        materials.unwrap_or_else(|| {
+            godot_error!(
+                "{}",
+                LoadError::new(
+                    LoadErrorKind::InvalidFragment,
+                    format!("Mesh {} has an invalid material list", self.name()),
+                )
+            );
+            Vec::new()
+        })
     }
 
     fn get_dmtrackdef(&self) -> Option<&DmTrackDef> {
@@ -552,27 +635,42 @@ struct DmSpriteProvider {
 pub struct S3DMesh {
     base: Base<RefCounted>,
     provider: Option<Box<dyn MeshProvider>>,
+    index: u32,
 }
 
 
 
 impl S3DFragment for S3DMesh {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) {
-        let fragment = wld.as_ref().at(index as usize - 1).unwrap();
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        let fragment = index
+            .checked_sub(1)
+            .and_then(|i| wld.as_ref().at(i as usize))
+            .ok_or_else(|| {
+                LoadError::new(LoadErrorKind::InvalidFragment, "Fragment index is out of bounds!")
+                    .with_fragment_index(index)
+            })?;
         let provider: Box<dyn MeshProvider> = match fragment {
             FragmentType::DmSpriteDef(_) => {
                 Box::new(DmSpriteProvider {
-                    fragment: create_fragment_ref(wld.clone(), index)
+                    fragment: create_fragment_ref(wld.clone(), index)?
                 })
             },
             FragmentType::DmSpriteDef2(_) => {
                 Box::new(DmSprite2Provider {
-                    fragment: create_fragment_ref(wld.clone(), index)
+                    fragment: create_fragment_ref(wld.clone(), index)?
                 })
             },
-            _ => panic!("S3DMesh trying to wrap a non-mesh fragment!")
+            _ => {
+                return Err(LoadError::new(
+                    LoadErrorKind::InvalidFragment,
+                    "S3DMesh trying to wrap a non-mesh fragment!",
+                )
+                .with_fragment_index(index))
+            }
         };
         self.provider = Some(provider);
+        self.index = index;
+        Ok(())
     }
 }
 
@@ -590,6 +688,12 @@ impl S3DMesh {
         self.get_provider().flags()
     }
 
+    /// The index of the fragment within the WLD.
+    #[func]
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
     #[func]
     pub fn center(&self) -> Vector3 {
         self.get_provider().center()
@@ -626,6 +730,22 @@ impl S3DMesh {
         self.get_provider().vertex_colors()
     }
 
+    /// Returns the vertex colors of the mesh with the given ambient light added, which reproduces the baked lighting of zones.
+    /// The ambient color for a zone mesh comes from `S3DWld.mesh_ambient_color()`.  See `lit_vertex_color` for the blend.
+    /// Meshes without vertex colors get the ambient color for every vertex.
+    #[func]
+    pub fn lit_vertex_colors(&self, ambient: Color) -> PackedColorArray {
+        let vertex_colors = self.vertex_colors();
+        if vertex_colors.is_empty() {
+            return vec![ambient; self.vertices().len()].into_iter().collect();
+        }
+        vertex_colors
+            .as_slice()
+            .iter()
+            .map(|&color| lit_vertex_color(color, ambient))
+            .collect()
+    }
+
     /// Returns the UV coordinates of the mesh, converted into Godot format.
     #[func]
     pub fn uvs(&self) -> PackedVector2Array {
@@ -649,13 +769,81 @@ impl S3DMesh {
     /// Returns an array of material groups.  
     /// Material groups are two-tuples.  The first element is the name of the material.  
     /// The second element is the array of indices for the polygons that use this material.
-    ///
-    /// Invisible materials are skipped entirely.
     #[func]
     pub fn face_material_groups(&self) -> Array<VariantArray> {
         self.get_provider().face_material_groups()
     }
 
+    /// Builds an ArrayMesh with one surface per material group.
+    ///
+    /// `materials` maps material names to Godot Materials.  A null value means the material is invisible,
+    /// and its polygons are skipped.  Materials missing from the dictionary produce a surface without a material.
+    /// If `vertex_colors_override` is not empty, it is used instead of the mesh's own vertex colors -
+    /// this is the case for actor instances, see `S3DActorInstance.vertex_colors()`.
+    /// If `skip_invisible` is true, polygons using invisible materials are skipped as well.
+    #[func]
+    pub fn to_array_mesh(
+        &self,
+        materials: Dictionary,
+        vertex_colors_override: PackedColorArray,
+        skip_invisible: bool,
+    ) -> Gd<ArrayMesh> {
+        let provider = self.get_provider();
+        self.build_array_mesh(
+            materials,
+            provider.vertices(),
+            provider.normals(),
+            vertex_colors_override,
+            skip_invisible,
+        )
+    }
+
+    /// Returns the vertex positions of a skinned mesh (see `S3DHierSprite.meshes()`), transformed into bind space.
+    /// The vertices of skinned meshes are stored relative to the bone they are assigned to.
+    /// `bone_transforms` are the transforms of each bone relative to the skeleton, from `S3DHierSprite.global_rest_transforms()`.
+    /// Vertices without a bone assignment are returned unchanged.
+    #[func]
+    pub fn bind_vertices(&self, bone_transforms: Array<Transform3D>) -> PackedVector3Array {
+        let transforms = self.vertex_bone_transforms(&bone_transforms);
+        self.vertices()
+            .as_slice()
+            .iter()
+            .zip(transforms.into_iter().chain(std::iter::repeat(Transform3D::IDENTITY)))
+            .map(|(&vertex, transform)| transform * vertex)
+            .collect()
+    }
+
+    /// Returns the vertex normals of a skinned mesh, rotated into bind space.  See `bind_vertices()`.
+    #[func]
+    pub fn bind_normals(&self, bone_transforms: Array<Transform3D>) -> PackedVector3Array {
+        let transforms = self.vertex_bone_transforms(&bone_transforms);
+        self.normals()
+            .as_slice()
+            .iter()
+            .zip(transforms.into_iter().chain(std::iter::repeat(Transform3D::IDENTITY)))
+            .map(|(&normal, transform)| (transform.basis * normal).normalized())
+            .collect()
+    }
+
+    /// Builds an ArrayMesh like `to_array_mesh()`, but with the vertices and normals in bind space (see `bind_vertices()`).
+    /// Use this with a Skeleton3D whose bone rests are set from `S3DHierSprite.rest_transforms()`,
+    /// so that the rest pose, the skin and the animations all line up.
+    #[func]
+    pub fn to_bind_pose_array_mesh(
+        &self,
+        materials: Dictionary,
+        bone_transforms: Array<Transform3D>,
+        skip_invisible: bool,
+    ) -> Gd<ArrayMesh> {
+        self.build_array_mesh(
+            materials,
+            self.bind_vertices(bone_transforms.clone()),
+            self.bind_normals(bone_transforms),
+            PackedColorArray::new(),
+            skip_invisible,
+        )
+    }
+
     /// Get all the indices that form polygons of the mesh.
     /// NOTE: This should not normally be used if you wish to actually apply materials to surfaces.
     /// To do so, you must get the indices of each material group, and add each material group as a separate surface.
@@ -670,6 +858,16 @@ impl S3DMesh {
         self.get_provider().collision_vertices()
     }
 
+    /// Builds a ConcavePolygonShape3D from `collision_vertices()`, without degenerate or duplicate triangles.
+    /// Like the vertices, the shape is relative to `center()`.
+    /// To merge the collision of a whole zone into a few shapes, use `S3DWld.build_collision()` instead.
+    #[func]
+    pub fn to_concave_shape(&self) -> Gd<ConcavePolygonShape3D> {
+        let mut builder = CollisionBuilder::new(0.);
+        builder.add_triangles(self.collision_vertices().as_slice());
+        builder.build().pop().unwrap_or_else(ConcavePolygonShape3D::new_gd)
+    }
+
     /// Return true if this mesh has vertex animations
     #[func]
     pub fn is_animated(&self) -> bool {
@@ -702,13 +900,85 @@ impl S3DMesh {
         self.provider.as_ref().unwrap()
     }
 
+    fn build_array_mesh(
+        &self,
+        materials: Dictionary,
+        vertices: PackedVector3Array,
+        normals: PackedVector3Array,
+        vertex_colors_override: PackedColorArray,
+        skip_invisible: bool,
+    ) -> Gd<ArrayMesh> {
+        let provider = self.get_provider();
+        let mut arrays = VariantArray::new();
+        arrays.resize(ArrayType::MAX.ord() as usize, &Variant::nil());
+        arrays.set(ArrayType::VERTEX.ord() as usize, &vertices.to_variant());
+        arrays.set(ArrayType::NORMAL.ord() as usize, &normals.to_variant());
+        let vertex_colors = if vertex_colors_override.is_empty() {
+            provider.vertex_colors()
+        } else {
+            vertex_colors_override
+        };
+        if !vertex_colors.is_empty() {
+            arrays.set(ArrayType::COLOR.ord() as usize, &vertex_colors.to_variant());
+        }
+        let uvs = provider.uvs();
+        if !uvs.is_empty() {
+            arrays.set(ArrayType::TEX_UV.ord() as usize, &uvs.to_variant());
+        }
+        let bone_indices = provider.bone_indices();
+        if !bone_indices.is_empty() {
+            arrays.set(ArrayType::BONES.ord() as usize, &bone_indices.to_variant());
+            arrays.set(ArrayType::WEIGHTS.ord() as usize, &provider.bone_weights().to_variant());
+        }
+
+        let mut mesh = ArrayMesh::new_gd();
+        for surface in provider.surfaces() {
+            if surface.indices.is_empty() || (skip_invisible && !surface.visible) {
+                continue;
+            }
+            let material = match materials.get(GString::from(&surface.material_name)) {
+                Some(variant) if variant.is_nil() => continue,
+                Some(variant) => variant.try_to::<Gd<Material>>().ok(),
+                None => {
+                    godot_error!("Missing material: {0}", surface.material_name);
+                    None
+                }
+            };
+            arrays.set(ArrayType::INDEX.ord() as usize, &surface.indices.to_variant());
+            mesh.add_surface_from_arrays(PrimitiveType::TRIANGLES, &arrays);
+            if let Some(material) = material {
+                let surface_index = mesh.get_surface_count() - 1;
+                mesh.surface_set_material(surface_index, &material);
+            }
+        }
+        mesh
+    }
+
+    /// The transform of the bone each vertex is assigned to, in the order of the skin assignment groups.
+    fn vertex_bone_transforms(&self, bone_transforms: &Array<Transform3D>) -> Vec<Transform3D> {
+        self.bone_indices()
+            .as_slice()
+            .iter()
+            .step_by(4)
+            .map(|&bone| {
+                usize::try_from(bone)
+                    .ok()
+                    .and_then(|bone| bone_transforms.get(bone))
+                    .unwrap_or(Transform3D::IDENTITY)
+            })
+            .collect()
+    }
+
     pub fn from_reference(wld: &Arc<WldDoc>, mesh_reference: &DmSprite) -> Option<Gd<Self>> {
         match mesh_reference.reference {
             FragmentRef::Index(index, _) => {
-                let fragment = wld.at(index as usize - 1).unwrap();
+                let fragment = wld.at((index as usize).checked_sub(1)?)?;
                 match fragment {
-                    FragmentType::DmSpriteDef2(_) => Some(gd_from_frag_type::<S3DMesh>(wld, index)),
-                    FragmentType::DmSpriteDef(_) => Some(gd_from_frag_type::<S3DMesh>(wld, index)),
+                    FragmentType::DmSpriteDef2(_) | FragmentType::DmSpriteDef(_) => {
+                        gd_from_frag_type::<S3DMesh>(wld, index)
+                            .map_err(|e| godot_error!("{e}"))
+                            .ok()
+                    }
                     _ => None,
                 }
             }
@@ -718,3 +988,28 @@ impl S3DMesh {
 
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ambient_light_is_added_to_vertex_colors() {
+        let color = Color::from_rgba(0.25, 0.5, 0., 0.75);
+        let ambient = Color::from_rgba(0.25, 0.25, 0.125, 1.);
+        assert_eq!(lit_vertex_color(color, ambient), Color::from_rgba(0.5, 0.75, 0.125, 0.75));
+    }
+
+    #[test]
+    fn lit_vertex_colors_are_clamped() {
+        let color = Color::from_rgba(0.75, 1., 0.5, 1.);
+        let ambient = Color::from_rgba(0.5, 0.5, 0.5, 1.);
+        assert_eq!(lit_vertex_color(color, ambient), Color::from_rgba(1., 1., 1., 1.));
+    }
+
+    #[test]
+    fn black_ambient_keeps_vertex_colors() {
+        let color = Color::from_rgba(0.2, 0.4, 0.6, 0.8);
+        assert_eq!(lit_vertex_color(color, Color::BLACK), color);
+    }
+}
@@ -1,16 +1,31 @@
 mod actordef;
 mod actorinst;
+mod character;
+mod collision;
 mod hiersprite;
+mod light;
 mod material;
 mod mesh;
+mod particle;
+mod region;
+mod sprite;
+mod sprite3d;
 pub use actordef::*;
 pub use actorinst::*;
+pub use character::*;
+pub use collision::*;
 use godot::classes::RefCounted;
 use godot::prelude::*;
 pub use hiersprite::*;
+pub use light::*;
 use libeq_wld::parser::{Fragment, FragmentType, WldDoc};
+use crate::error::{LoadError, LoadErrorKind};
 pub use material::*;
 pub use mesh::*;
+pub use particle::*;
+pub use region::*;
+pub use sprite::*;
+pub use sprite3d::*;
 use owning_ref::ArcRef;
 use std::sync::Arc;
 
@@ -30,18 +45,32 @@ fn frag_to_dict<T: 'static + Fragment + serde::ser::Serialize>(wld: &WldDoc, fra
 }
 
 /// Create a reference to a particular fragment by pairing it with its parent WLD in an OwnedRef.
-fn create_fragment_ref<T: 'static + Fragment>(wld: Arc<WldDoc>, index: u32) -> ArcRef<WldDoc, T> {
-    ArcRef::new(wld).map(|wld| {
-        wld.at((index - 1) as usize)
-            .expect(format!("Fragment index {index} is out of bounds!").as_str())
+fn create_fragment_ref<T: 'static + Fragment>(
+    wld: Arc<WldDoc>,
+    index: u32,
+) -> Result<ArcRef<WldDoc, T>, LoadError> {
+    ArcRef::new(wld).try_map(|wld| {
+        index
+            .checked_sub(1)
+            .and_then(|i| wld.at(i as usize))
+            .ok_or_else(|| {
+                LoadError::new(LoadErrorKind::InvalidFragment, "Fragment index is out of bounds!")
+                    .with_fragment_index(index)
+            })?
             .as_any()
             .downcast_ref()
-            .expect(format!("Fragment at index {index} is not of the requested type!").as_str())
+            .ok_or_else(|| {
+                LoadError::new(
+                    LoadErrorKind::InvalidFragment,
+                    "Fragment is not of the requested type!",
+                )
+                .with_fragment_index(index)
+            })
     })
 }
 
 pub trait S3DFragment {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32);
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError>;
 }
 
 #[derive(GodotClass)]
@@ -55,9 +84,10 @@ pub struct S3DUnknownFragment {
 }
 
 impl S3DFragment for S3DUnknownFragment {
-    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
         self.index = index;
-        self.wld = Some(wld.clone())
+        self.wld = Some(wld.clone());
+        Ok(())
     }
 }
 
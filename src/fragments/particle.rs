@@ -0,0 +1,454 @@
+use godot::classes::base_material_3d::{
+    BillboardMode, BlendMode, Flags, ShadingMode, TextureParam, Transparency,
+};
+use godot::classes::{
+    cpu_particles_3d, particle_process_material, CpuParticles3D, GpuParticles3D,
+    ParticleProcessMaterial, ProjectSettings, QuadMesh, RefCounted, StandardMaterial3D, Texture2D,
+};
+use godot::prelude::*;
+use libeq_wld::parser::{FragmentRef, ParticleCloudDef, ParticleSprite, ParticleSpriteDef, WldDoc};
+use std::sync::Arc;
+extern crate owning_ref;
+use crate::error::LoadError;
+use crate::wld::gd_from_frag_type;
+use super::{blit_sprite_texture_filenames, create_fragment_ref, S3DBlitSprite, S3DFragment};
+use crate::util::{u32_to_color, wld_f32_pos_to_gd, WORLD_SCALE};
+use owning_ref::ArcRef;
+#[cfg(feature = "serde")]
+use super::frag_to_dict;
+
+/// How the particles of a cloud are spawned and move.
+/// These values are exposed to GDScript as the `MOVEMENT_*` constants on `S3DParticleCloud`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleMovement {
+    /// Spawned within a sphere, moving outwards
+    Sphere = 1,
+    /// Spawned on a flat disc
+    Plane = 2,
+    /// Spawned at a point, moving in the spawn direction and falling, like a fountain
+    Stream = 3,
+    None = 4,
+}
+
+impl ParticleMovement {
+    pub fn from_id(id: u32) -> Self {
+        match id {
+            1 => ParticleMovement::Sphere,
+            2 => ParticleMovement::Plane,
+            3 => ParticleMovement::Stream,
+            _ => ParticleMovement::None,
+        }
+    }
+}
+
+/// Where the particles of an emitter are spawned, in Godot units.
+enum EmitterShape {
+    Sphere(f32),
+    Box(Vector3),
+    Point,
+}
+
+/// The emitter settings shared by `to_gpu_particles` and `to_cpu_particles`.
+struct EmitterSettings {
+    shape: EmitterShape,
+    direction: Vector3,
+    spread: f32,
+    velocity: f32,
+    gravity: Vector3,
+    color: Color,
+    amount: i32,
+    lifetime: f64,
+}
+
+/// Godot's default for `physics/3d/default_gravity`, used if the project setting can't be read.
+const DEFAULT_GRAVITY: f32 = 9.8;
+
+/// The gravity of the Godot world, from the `physics/3d/default_gravity` and `physics/3d/default_gravity_vector` project settings.
+fn project_gravity() -> Vector3 {
+    let settings = ProjectSettings::singleton();
+    let magnitude = settings
+        .get_setting("physics/3d/default_gravity")
+        .try_to::<f32>()
+        .unwrap_or(DEFAULT_GRAVITY);
+    let direction = settings
+        .get_setting("physics/3d/default_gravity_vector")
+        .try_to::<Vector3>()
+        .unwrap_or(Vector3::DOWN);
+    direction * magnitude
+}
+
+/// A wrapper around `PARTICLECLOUDDEF`, which defines a particle emitter such as the flames of a torch or the spray of a fountain.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DParticleCloud {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, ParticleCloudDef>>,
+}
+
+impl S3DFragment for S3DParticleCloud {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DParticleCloud {
+    #[constant]
+    const MOVEMENT_SPHERE: i32 = ParticleMovement::Sphere as i32;
+    #[constant]
+    const MOVEMENT_PLANE: i32 = ParticleMovement::Plane as i32;
+    #[constant]
+    const MOVEMENT_STREAM: i32 = ParticleMovement::Stream as i32;
+    #[constant]
+    const MOVEMENT_NONE: i32 = ParticleMovement::None as i32;
+
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The shape of the emitter, as one of the `MOVEMENT_*` constants.
+    #[func]
+    pub fn movement(&self) -> i32 {
+        self.get_movement() as i32
+    }
+
+    /// The maximum number of particles alive at the same time.
+    #[func]
+    pub fn simultaneous_particles(&self) -> u32 {
+        self.get_frag().simultaneous_particles
+    }
+
+    /// The number of particles spawned per second.
+    #[func]
+    pub fn spawn_rate(&self) -> u32 {
+        self.get_frag().spawn_rate
+    }
+
+    /// The radius of the emitter, in Godot units.
+    #[func]
+    pub fn spawn_radius(&self) -> f32 {
+        self.get_frag().spawn_radius * WORLD_SCALE
+    }
+
+    /// The angle in degrees that particles spread out from the spawn direction.
+    #[func]
+    pub fn spawn_angle(&self) -> f32 {
+        self.get_frag().spawn_angle
+    }
+
+    /// The lifetime of each particle in seconds.
+    #[func]
+    pub fn lifetime(&self) -> f32 {
+        self.get_frag().spawn_lifespan as f32 * 0.001
+    }
+
+    /// The initial speed of each particle, in Godot units per second.
+    #[func]
+    pub fn velocity(&self) -> f32 {
+        self.get_frag().spawn_velocity * WORLD_SCALE
+    }
+
+    /// The direction particles are spawned in, in Godot coordinates.
+    #[func]
+    pub fn direction(&self) -> Vector3 {
+        let direction = wld_f32_pos_to_gd(&self.get_frag().spawn_normal);
+        if direction == Vector3::ZERO {
+            Vector3::UP
+        } else {
+            direction.normalized()
+        }
+    }
+
+    /// An approximation of the gravity applied to the particles, in Godot units per second squared.
+    /// The `PARTICLECLOUDDEF` has no gravity value, so this guesses that streams (fountains, waterfalls) fall with the
+    /// project's default 3D gravity and that other clouds float.  `to_gpu_particles` and `to_cpu_particles` only use it
+    /// when asked to.
+    #[func]
+    pub fn gravity(&self) -> Vector3 {
+        match self.get_movement() {
+            ParticleMovement::Stream => project_gravity(),
+            _ => Vector3::ZERO,
+        }
+    }
+
+    /// The size of each particle, in Godot units.
+    #[func]
+    pub fn size(&self) -> f32 {
+        self.get_frag().spawn_scale * WORLD_SCALE
+    }
+
+    #[func]
+    pub fn color(&self) -> Color {
+        u32_to_color(&self.get_frag().color)
+    }
+
+    /// The name of the `BLITSPRITE` drawn for each particle.
+    #[func]
+    pub fn sprite_name(&self) -> GString {
+        let wld = self.get_wld();
+        wld.get(&self.get_frag().blitsprite)
+            .and_then(|sprite| wld.get_string(sprite.name_reference))
+            .map(GString::from)
+            .unwrap_or_default()
+    }
+
+    /// The sprite drawn for each particle.
+    #[func]
+    pub fn sprite(&self) -> Option<Gd<S3DBlitSprite>> {
+        let wld = self.get_wld();
+        S3DBlitSprite::from_reference(wld, wld.get(&self.get_frag().blitsprite)?)
+    }
+
+    /// The texture filenames of the sprite drawn for each particle.  Animated sprites have more than one.
+    #[func]
+    pub fn texture_filenames(&self) -> PackedStringArray {
+        blit_sprite_texture_filenames(self.get_wld(), &self.get_frag().blitsprite)
+    }
+
+    /// Builds a GPUParticles3D with a configured ParticleProcessMaterial, drawing the given texture on billboarded quads.
+    /// If `apply_gravity` is true, the particles fall with the approximate gravity from `gravity()`.
+    #[func]
+    pub fn to_gpu_particles(&self, texture: Option<Gd<Texture2D>>, apply_gravity: bool) -> Gd<GpuParticles3D> {
+        use particle_process_material::{EmissionShape, Parameter};
+        let settings = self.emitter_settings(apply_gravity);
+        let mut process_material = ParticleProcessMaterial::new_gd();
+        match settings.shape {
+            EmitterShape::Sphere(radius) => {
+                process_material.set_emission_shape(EmissionShape::SPHERE);
+                process_material.set_emission_sphere_radius(radius);
+            }
+            EmitterShape::Box(extents) => {
+                process_material.set_emission_shape(EmissionShape::BOX);
+                process_material.set_emission_box_extents(extents);
+            }
+            EmitterShape::Point => process_material.set_emission_shape(EmissionShape::POINT),
+        }
+        process_material.set_direction(settings.direction);
+        process_material.set_spread(settings.spread);
+        process_material.set_param_min(Parameter::INITIAL_LINEAR_VELOCITY, settings.velocity);
+        process_material.set_param_max(Parameter::INITIAL_LINEAR_VELOCITY, settings.velocity);
+        process_material.set_gravity(settings.gravity);
+        process_material.set_color(settings.color);
+
+        let mut particles = GpuParticles3D::new_alloc();
+        particles.set_name(&self.name());
+        particles.set_amount(settings.amount);
+        particles.set_lifetime(settings.lifetime);
+        particles.set_process_material(&process_material);
+        particles.set_draw_pass_mesh(0, &self.build_quad(texture));
+        particles
+    }
+
+    /// Builds a CPUParticles3D configured like `to_gpu_particles`, for renderers without GPU particle support.
+    #[func]
+    pub fn to_cpu_particles(&self, texture: Option<Gd<Texture2D>>, apply_gravity: bool) -> Gd<CpuParticles3D> {
+        use cpu_particles_3d::{EmissionShape, Parameter};
+        let settings = self.emitter_settings(apply_gravity);
+        let mut particles = CpuParticles3D::new_alloc();
+        particles.set_name(&self.name());
+        match settings.shape {
+            EmitterShape::Sphere(radius) => {
+                particles.set_emission_shape(EmissionShape::SPHERE);
+                particles.set_emission_sphere_radius(radius);
+            }
+            EmitterShape::Box(extents) => {
+                particles.set_emission_shape(EmissionShape::BOX);
+                particles.set_emission_box_extents(extents);
+            }
+            EmitterShape::Point => particles.set_emission_shape(EmissionShape::POINT),
+        }
+        particles.set_direction(settings.direction);
+        particles.set_spread(settings.spread);
+        particles.set_param_min(Parameter::INITIAL_LINEAR_VELOCITY, settings.velocity);
+        particles.set_param_max(Parameter::INITIAL_LINEAR_VELOCITY, settings.velocity);
+        particles.set_gravity(settings.gravity);
+        particles.set_color(settings.color);
+        particles.set_amount(settings.amount);
+        particles.set_lifetime(settings.lifetime);
+        particles.set_mesh(&self.build_quad(texture));
+        particles
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DParticleCloud {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &ParticleCloudDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    fn get_movement(&self) -> ParticleMovement {
+        ParticleMovement::from_id(self.get_frag().particle_movement)
+    }
+
+    /// The number of particles for Godot's emitters.
+    /// Godot spawns `amount / lifetime` particles per second, so this is the smaller of the spawn rate over a lifetime
+    /// and the number of simultaneous particles.
+    fn amount(&self) -> i32 {
+        let from_rate = (self.spawn_rate() as f32 * self.lifetime()).ceil() as u32;
+        let amount = match from_rate {
+            0 => self.simultaneous_particles(),
+            _ => from_rate.min(self.simultaneous_particles().max(1)),
+        };
+        amount.max(1) as i32
+    }
+
+    /// The settings of Godot's emitters, from the fragment.  Gravity is only set if `apply_gravity` is true.
+    fn emitter_settings(&self, apply_gravity: bool) -> EmitterSettings {
+        let shape = match self.get_movement() {
+            ParticleMovement::Sphere => EmitterShape::Sphere(self.spawn_radius()),
+            ParticleMovement::Plane => EmitterShape::Box(self.plane_extents()),
+            ParticleMovement::Stream | ParticleMovement::None => EmitterShape::Point,
+        };
+        EmitterSettings {
+            shape,
+            direction: self.direction(),
+            spread: self.spawn_angle(),
+            velocity: self.velocity(),
+            gravity: if apply_gravity { self.gravity() } else { Vector3::ZERO },
+            color: self.color(),
+            amount: self.amount(),
+            lifetime: self.lifetime().max(0.01) as f64,
+        }
+    }
+
+    /// The extents of a flat box standing in for the disc of plane emitters.
+    fn plane_extents(&self) -> Vector3 {
+        let radius = self.spawn_radius();
+        Vector3::new(radius, 0., radius)
+    }
+
+    /// A quad that always faces the camera, drawing the particle texture additively.
+    fn build_quad(&self, texture: Option<Gd<Texture2D>>) -> Gd<QuadMesh> {
+        let mut material = StandardMaterial3D::new_gd();
+        material.set_billboard_mode(BillboardMode::PARTICLES);
+        material.set_flag(Flags::ALBEDO_FROM_VERTEX_COLOR, true);
+        material.set_transparency(Transparency::ALPHA);
+        material.set_blend_mode(BlendMode::ADD);
+        material.set_shading_mode(ShadingMode::UNSHADED);
+        if let Some(texture) = texture {
+            material.set_texture(TextureParam::ALBEDO, &texture);
+        }
+        let mut quad = QuadMesh::new_gd();
+        quad.set_size(Vector2::new(self.size(), self.size()));
+        quad.set_material(&material);
+        quad
+    }
+}
+
+/// A wrapper around `PARTICLESPRITEDEF`, an older kind of particle sprite defined by a set of points.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DParticleSprite {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, ParticleSpriteDef>>,
+}
+
+impl S3DFragment for S3DParticleSprite {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DParticleSprite {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The positions particles are drawn at, in Godot coordinates.
+    #[func]
+    pub fn vertices(&self) -> PackedVector3Array {
+        self.get_frag()
+            .vertices
+            .iter()
+            .map(wld_f32_pos_to_gd)
+            .collect()
+    }
+
+    /// The center of the sprite, in Godot coordinates.
+    #[func]
+    pub fn center(&self) -> Vector3 {
+        self.get_frag()
+            .center_offset
+            .as_ref()
+            .map(wld_f32_pos_to_gd)
+            .unwrap_or(Vector3::ZERO)
+    }
+
+    /// The radius of the sprite, in Godot units.
+    #[func]
+    pub fn radius(&self) -> f32 {
+        self.get_frag().radius.unwrap_or(0.) * WORLD_SCALE
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DParticleSprite {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &ParticleSpriteDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    /// Creates an S3DParticleSprite from a `PARTICLESPRITE` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, sprite: &ParticleSprite) -> Option<Gd<Self>> {
+        match sprite.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DParticleSprite>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use godot::classes::RefCounted;
+use godot::prelude::*;
+use libeq_wld::parser::{DmSpriteDef2, FragmentRef, Region, WldDoc, WorldNode, WorldTree, Zone};
+use std::sync::Arc;
+extern crate owning_ref;
+use crate::error::LoadError;
+use super::{create_fragment_ref, S3DFragment};
+use crate::util::{gd_pos_to_wld, wld_f32_pos_to_gd};
+use owning_ref::ArcRef;
+#[cfg(feature = "serde")]
+use super::frag_to_dict;
+
+/// The type of a region, decoded from the names of the `ZONE` fragments that list it.
+/// These values are exposed to GDScript as the `REGION_TYPE_*` constants on `S3DZoneRegions`.
+/// Source: LanternExtractor
+/// (https://github.com/LanternEQ/LanternExtractor/blob/afe174b71ac9f9ab75e259bac2282735b093426d/LanternExtractor/EQ/Wld/Fragments/BspRegionType.cs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionType {
+    Normal = 0,
+    Water = 1,
+    Lava = 2,
+    /// Teleports the player to another zone, or another place in the same zone.
+    Zoneline = 3,
+    Pvp = 4,
+    Slippery = 5,
+    /// Water that blocks line of sight
+    WaterBlockLos = 6,
+    FreezingWater = 7,
+    Unknown = 8,
+}
+
+impl RegionType {
+    /// True for every kind of water.
+    pub fn is_water(&self) -> bool {
+        matches!(
+            self,
+            RegionType::Water | RegionType::WaterBlockLos | RegionType::FreezingWater
+        )
+    }
+
+    /// Decodes the region types from the name of a `ZONE` fragment,
+    /// e.g. "WTN__01521000000000000000000000___000000000000" is water.
+    pub fn from_zone_name(name: &str) -> Vec<RegionType> {
+        let name = name.to_lowercase();
+        if name.starts_with("wtntp") {
+            vec![RegionType::Water, RegionType::Zoneline]
+        } else if name.starts_with("wtn_") || name.starts_with("wt_") {
+            vec![RegionType::Water]
+        } else if name.starts_with("lantp") {
+            vec![RegionType::Lava, RegionType::Zoneline]
+        } else if name.starts_with("lan_") || name.starts_with("la_") {
+            vec![RegionType::Lava]
+        } else if name.starts_with("drntp") {
+            vec![RegionType::Zoneline]
+        } else if name.starts_with("drp_") {
+            vec![RegionType::Pvp]
+        } else if name.starts_with("drn_") {
+            if name.contains("_s_") {
+                vec![RegionType::Slippery]
+            } else {
+                vec![RegionType::Unknown]
+            }
+        } else if name.starts_with("sln_") {
+            vec![RegionType::WaterBlockLos]
+        } else if name.starts_with("vwn_") {
+            vec![RegionType::FreezingWater]
+        } else {
+            vec![RegionType::Normal]
+        }
+    }
+}
+
+/// The destination of a zoneline region.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zoneline {
+    /// Teleports to the given position and heading in the given zone.
+    /// Coordinates of 999999 mean the player keeps their current coordinate on that axis.
+    Absolute {
+        zone_id: u32,
+        position: (f32, f32, f32),
+        heading: u32,
+    },
+    /// Teleports to an entry of the server-side zone point table.
+    Reference { index: u32 },
+}
+
+/// The zone id used by zonelines that refer to the server-side zone point table.
+const ZONELINE_REFERENCE_ZONE_ID: u32 = 255;
+
+impl Zoneline {
+    /// Decodes the destination from the name of a zoneline `ZONE` fragment.
+    /// The name is a 5 character prefix, followed by the zone id (5 digits).  That is followed either by the
+    /// index of the zone point (6 digits), or by the x, y and z coordinates (6 digits each) and heading (3 digits).
+    pub fn from_zone_name(name: &str) -> Option<Zoneline> {
+        let field = |start: usize, len: usize| name.get(start..start + len).map(str::trim);
+        let zone_id = field(5, 5)?.parse().ok()?;
+        if zone_id == ZONELINE_REFERENCE_ZONE_ID {
+            return Some(Zoneline::Reference {
+                index: field(10, 6)?.parse().ok()?,
+            });
+        }
+        Some(Zoneline::Absolute {
+            zone_id,
+            position: (
+                field(10, 6)?.parse().ok()?,
+                field(16, 6)?.parse().ok()?,
+                field(22, 6)?.parse().ok()?,
+            ),
+            heading: field(28, 3)?.parse().ok()?,
+        })
+    }
+}
+
+/// What is known about a region from the `ZONE` fragments that list it.
+#[derive(Debug, Clone, Default)]
+pub struct RegionInfo {
+    pub types: Vec<RegionType>,
+    pub zone_names: Vec<String>,
+    pub zoneline: Option<Zoneline>,
+}
+
+/// Returns the region number stored in a node of the BSP tree.  Region numbers start at 1, and 0 means the node is not a leaf.
+/// Note that this is the position of the region among the `REGION` fragments, not a fragment index.
+fn node_region(node: &WorldNode) -> u32 {
+    match node.region {
+        FragmentRef::Index(region, _) => region,
+        _ => 0,
+    }
+}
+
+/// Returns the fragment index of the mesh of each region, in the order of the regions.
+/// Zone meshes are split up by region, but regions such as water volumes may have no mesh.
+pub fn region_mesh_indices(wld: &WldDoc) -> Vec<Option<u32>> {
+    wld.iter()
+        .filter_map(|fragment| fragment.as_any().downcast_ref::<Region>())
+        .map(|region| match region.mesh_reference {
+            Some(FragmentRef::Index(index, _)) => Some(index),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A convex polygon in EQ coordinates.
+type Polygon = Vec<Vector3>;
+
+/// Clips a convex polyhedron, given by its faces, to the side of the plane where `normal.dot(p) + distance` has the given sign.
+/// The face that closes the cut is added to the result.
+fn clip_polyhedron(faces: &[Polygon], normal: Vector3, distance: f32, front: bool) -> Vec<Polygon> {
+    let sign = if front { 1. } else { -1. };
+    let side = |p: Vector3| sign * (normal.dot(p) + distance);
+    let mut clipped = vec![];
+    let mut cut_points: Vec<Vector3> = vec![];
+    for face in faces {
+        let mut polygon = vec![];
+        for (i, &a) in face.iter().enumerate() {
+            let b = face[(i + 1) % face.len()];
+            let (side_a, side_b) = (side(a), side(b));
+            if side_a >= 0. {
+                polygon.push(a);
+            }
+            if (side_a >= 0.) != (side_b >= 0.) {
+                let point = a + (b - a) * (side_a / (side_a - side_b));
+                polygon.push(point);
+                cut_points.push(point);
+            }
+        }
+        if polygon.len() >= 3 {
+            clipped.push(polygon);
+        }
+    }
+
+    // The points along the cut form the new face, and only need to be sorted around their center.
+    cut_points.dedup_by(|a, b| a.distance_to(*b) < 0.001);
+    if cut_points.len() >= 3 {
+        let center = cut_points.iter().fold(Vector3::ZERO, |sum, &p| sum + p) / cut_points.len() as f32;
+        let u = (cut_points[0] - center).normalized();
+        let v = normal.cross(u);
+        cut_points.sort_by(|&a, &b| {
+            let angle = |p: Vector3| (p - center).dot(v).atan2((p - center).dot(u));
+            angle(a).total_cmp(&angle(b))
+        });
+        cut_points.dedup_by(|a, b| a.distance_to(*b) < 0.001);
+        clipped.push(cut_points);
+    }
+    clipped
+}
+
+/// Returns the faces of a box with the given corners.
+fn box_faces(min: Vector3, max: Vector3) -> Vec<Polygon> {
+    let corner = |x: bool, y: bool, z: bool| {
+        Vector3::new(
+            if x { max.x } else { min.x },
+            if y { max.y } else { min.y },
+            if z { max.z } else { min.z },
+        )
+    };
+    [
+        // -X, +X, -Y, +Y, -Z, +Z
+        [(0, 0, 0), (0, 1, 0), (0, 1, 1), (0, 0, 1)],
+        [(1, 0, 0), (1, 0, 1), (1, 1, 1), (1, 1, 0)],
+        [(0, 0, 0), (0, 0, 1), (1, 0, 1), (1, 0, 0)],
+        [(0, 1, 0), (1, 1, 0), (1, 1, 1), (0, 1, 1)],
+        [(0, 0, 0), (1, 0, 0), (1, 1, 0), (0, 1, 0)],
+        [(0, 0, 1), (0, 1, 1), (1, 1, 1), (1, 0, 1)],
+    ]
+    .iter()
+    .map(|face| face.iter().map(|&(x, y, z)| corner(x == 1, y == 1, z == 1)).collect())
+    .collect()
+}
+
+/// The regions of a zone, from the `WORLDTREE` BSP tree and the `REGION` and `ZONE` fragments.
+/// Use `S3DWld.zone_regions()` on the main WLD of a zone to get this.
+///
+/// Regions are numbered from 0, in the order of the `REGION` fragments in the WLD.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DZoneRegions {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, WorldTree>>,
+    regions: Vec<RegionInfo>,
+}
+
+impl S3DFragment for S3DZoneRegions {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        let region_count = wld
+            .iter()
+            .filter(|fragment| fragment.as_any().downcast_ref::<Region>().is_some())
+            .count();
+        self.regions = vec![RegionInfo::default(); region_count];
+        for zone in wld.iter().filter_map(|fragment| fragment.as_any().downcast_ref::<Zone>()) {
+            let name = wld.get_string(zone.name_reference).unwrap_or("");
+            let types = RegionType::from_zone_name(name);
+            let zoneline = if types.contains(&RegionType::Zoneline) {
+                Zoneline::from_zone_name(name)
+            } else {
+                None
+            };
+            // The regions of a ZONE start at 0, unlike the regions of the WORLDTREE.
+            for &region in &zone.regions {
+                if let Some(info) = self.regions.get_mut(region as usize) {
+                    info.types.extend(types.iter().filter(|t| !info.types.contains(t)));
+                    info.zone_names.push(String::from(name));
+                    info.zoneline = info.zoneline.or(zoneline);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DZoneRegions {
+    #[constant]
+    const REGION_TYPE_NORMAL: i32 = RegionType::Normal as i32;
+    #[constant]
+    const REGION_TYPE_WATER: i32 = RegionType::Water as i32;
+    #[constant]
+    const REGION_TYPE_LAVA: i32 = RegionType::Lava as i32;
+    #[constant]
+    const REGION_TYPE_ZONELINE: i32 = RegionType::Zoneline as i32;
+    #[constant]
+    const REGION_TYPE_PVP: i32 = RegionType::Pvp as i32;
+    #[constant]
+    const REGION_TYPE_SLIPPERY: i32 = RegionType::Slippery as i32;
+    #[constant]
+    const REGION_TYPE_WATER_BLOCK_LOS: i32 = RegionType::WaterBlockLos as i32;
+    #[constant]
+    const REGION_TYPE_FREEZING_WATER: i32 = RegionType::FreezingWater as i32;
+    #[constant]
+    const REGION_TYPE_UNKNOWN: i32 = RegionType::Unknown as i32;
+
+    /// The number of regions in the zone.
+    #[func]
+    pub fn region_count(&self) -> i32 {
+        self.regions.len() as i32
+    }
+
+    /// Returns the region containing the given position, in Godot coordinates.
+    /// Returns -1 if the position is outside of the BSP tree.
+    #[func]
+    pub fn region_at(&self, position: Vector3) -> i32 {
+        self.find_region(position).map_or(-1, |region| region as i32)
+    }
+
+    /// Returns the types of the given region, as `REGION_TYPE_*` constants.
+    /// A region can have more than one type, e.g. water that is also a zoneline.
+    /// The array is empty if the region does not exist.
+    #[func]
+    pub fn region_types(&self, region: i32) -> PackedInt32Array {
+        self.get_types(region)
+            .iter()
+            .map(|region_type| *region_type as i32)
+            .collect()
+    }
+
+    /// Returns the types of the region containing the given position, as `REGION_TYPE_*` constants.
+    /// The array is empty if the position is outside the zone's BSP tree.
+    #[func]
+    pub fn region_types_at(&self, position: Vector3) -> PackedInt32Array {
+        self.region_types(self.region_at(position))
+    }
+
+    /// Returns the names of the `ZONE` fragments that list the region.  The region types are decoded from these names.
+    #[func]
+    pub fn region_zone_names(&self, region: i32) -> PackedStringArray {
+        self.get_info(region)
+            .map(|info| info.zone_names.iter().map(GString::from).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the destination of a zoneline region, or an empty Dictionary if the region is not a zoneline.
+    ///
+    /// Zonelines to a fixed destination have the keys `zone_id`, `position` (in Godot coordinates) and `heading` (0 to 511).
+    /// A coordinate of 999999 in EQ units means the player keeps their current coordinate on that axis,
+    /// so `position` is only converted if none of the coordinates are 999999 - otherwise `eq_position` should be used.
+    ///
+    /// Zonelines that refer to the server-side zone point table have the keys `zone_id` (255) and `index`.
+    #[func]
+    pub fn zoneline(&self, region: i32) -> Dictionary {
+        let mut dict = Dictionary::new();
+        match self.get_info(region).and_then(|info| info.zoneline) {
+            Some(Zoneline::Absolute {
+                zone_id,
+                position,
+                heading,
+            }) => {
+                dict.set("zone_id", zone_id);
+                let keep = |v: f32| v == 999999.;
+                if !(keep(position.0) || keep(position.1) || keep(position.2)) {
+                    dict.set("position", wld_f32_pos_to_gd(&position));
+                }
+                dict.set("eq_position", Vector3::new(position.0, position.1, position.2));
+                dict.set("heading", heading);
+            }
+            Some(Zoneline::Reference { index }) => {
+                dict.set("zone_id", ZONELINE_REFERENCE_ZONE_ID);
+                dict.set("index", index);
+            }
+            None => {}
+        }
+        dict
+    }
+
+    /// Returns the convex polygons that enclose the given region, in Godot coordinates.  This is intended for debugging.
+    /// The cells of the BSP tree are clipped to the bounds of the zone's meshes, so regions at the edge of the zone are closed.
+    #[func]
+    pub fn region_polygons(&self, region: i32) -> Array<PackedVector3Array> {
+        self.cell_polygons(region)
+            .iter()
+            .map(|polygon| {
+                polygon
+                    .iter()
+                    .map(|p| wld_f32_pos_to_gd(&(p.x, p.y, p.z)))
+                    .collect::<PackedVector3Array>()
+            })
+            .collect()
+    }
+
+    /// Returns the bounding box of the given region, in Godot coordinates.  This is intended for debugging.
+    #[func]
+    pub fn region_aabb(&self, region: i32) -> Aabb {
+        let mut points = self
+            .cell_polygons(region)
+            .into_iter()
+            .flatten()
+            .map(|p| wld_f32_pos_to_gd(&(p.x, p.y, p.z)));
+        let Some(first) = points.next() else {
+            return Aabb::default();
+        };
+        points.fold(Aabb::new(first, Vector3::ZERO), |aabb, p| aabb.expand(p))
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DZoneRegions {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &WorldTree {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    fn get_info(&self, region: i32) -> Option<&RegionInfo> {
+        usize::try_from(region).ok().and_then(|region| self.regions.get(region))
+    }
+
+    /// Returns the types of the given region.  Regions that no `ZONE` fragment lists are normal.
+    /// Regions that don't exist, such as -1 for positions outside the BSP tree, have no types.
+    pub fn get_types(&self, region: i32) -> &[RegionType] {
+        match self.get_info(region) {
+            Some(info) if !info.types.is_empty() => &info.types,
+            Some(_) => &[RegionType::Normal],
+            None => &[],
+        }
+    }
+
+    /// Walks the BSP tree to find the region (starting at 0) containing the given position, in Godot coordinates.
+    pub fn find_region(&self, position: Vector3) -> Option<usize> {
+        let (x, y, z) = gd_pos_to_wld(position);
+        let position = Vector3::new(x, y, z);
+        let nodes = &self.get_frag().world_nodes;
+        let mut node = nodes.first()?;
+        // Each step goes one level deeper, so a valid tree can't take more steps than it has nodes.
+        for _ in 0..nodes.len() {
+            let region = node_region(node);
+            if region != 0 {
+                return Some(region as usize - 1);
+            }
+            let normal = Vector3::new(node.normal.0, node.normal.1, node.normal.2);
+            let next = if normal.dot(position) + node.split_distance > 0. {
+                node.front_tree
+            } else {
+                node.back_tree
+            };
+            node = nodes.get((next as usize).checked_sub(1)?)?;
+        }
+        None
+    }
+
+    /// Returns the bounds of all meshes in the WLD, in EQ coordinates, with some padding.
+    fn zone_bounds(&self) -> (Vector3, Vector3) {
+        let mut points = self
+            .get_wld()
+            .iter()
+            .filter_map(|fragment| fragment.as_any().downcast_ref::<DmSpriteDef2>())
+            .flat_map(|mesh| {
+                let scale = 1.0 / (1 << mesh.scale) as f32;
+                let center = Vector3::new(mesh.center.0, mesh.center.1, mesh.center.2);
+                mesh.positions
+                    .iter()
+                    .map(move |p| center + Vector3::new(p.0 as f32, p.1 as f32, p.2 as f32) * scale)
+            });
+        let padding = Vector3::splat(10.);
+        match points.next() {
+            Some(first) => {
+                let (min, max) = points.fold((first, first), |(min, max), p| {
+                    (min.coord_min(p), max.coord_max(p))
+                });
+                (min - padding, max + padding)
+            }
+            None => (Vector3::splat(-10000.), Vector3::splat(10000.)),
+        }
+    }
+
+    /// Returns the faces of the BSP cells (in EQ coordinates) belonging to the given region.
+    fn cell_polygons(&self, region: i32) -> Vec<Polygon> {
+        let Ok(region) = u32::try_from(region + 1) else {
+            return vec![];
+        };
+        let nodes = &self.get_frag().world_nodes;
+        let (min, max) = self.zone_bounds();
+        let mut polygons = vec![];
+        // Each entry is a node index (starting at 1), and the faces of its cell.
+        let mut stack = vec![(1, box_faces(min, max))];
+        // A valid tree visits each node once, so this guards against malformed trees with cycles.
+        let mut visits = 0;
+        while let Some((index, faces)) = stack.pop() {
+            visits += 1;
+            if visits > nodes.len() {
+                break;
+            }
+            let Some(node) = (index as usize).checked_sub(1).and_then(|i| nodes.get(i)) else {
+                continue;
+            };
+            if faces.is_empty() {
+                continue;
+            }
+            if node_region(node) != 0 {
+                if node_region(node) == region {
+                    polygons.extend(faces);
+                }
+                continue;
+            }
+            let normal = Vector3::new(node.normal.0, node.normal.1, node.normal.2);
+            if node.front_tree != 0 {
+                stack.push((node.front_tree, clip_polyhedron(&faces, normal, node.split_distance, true)));
+            }
+            if node.back_tree != 0 {
+                stack.push((node.back_tree, clip_polyhedron(&faces, normal, node.split_distance, false)));
+            }
+        }
+        polygons
+    }
+}
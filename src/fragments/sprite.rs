@@ -0,0 +1,404 @@
+use godot::classes::base_material_3d::BillboardMode;
+use godot::classes::{AnimatedSprite3D, RefCounted, Sprite3D, SpriteFrames, Texture2D};
+use godot::prelude::*;
+use libeq_wld::parser::{
+    BlitSprite, BlitSpriteDef, BmInfo, FragmentRef, SimpleSprite, SimpleSpriteDef, Sprite2D,
+    Sprite2DDef, WldDoc,
+};
+use std::sync::Arc;
+extern crate owning_ref;
+use crate::error::LoadError;
+use crate::wld::gd_from_frag_type;
+use super::{create_fragment_ref, S3DFragment};
+use crate::util::{wld_f32_pos_to_gd, WORLD_SCALE};
+use owning_ref::ArcRef;
+#[cfg(feature = "serde")]
+use super::frag_to_dict;
+
+/// The name of the animation in the SpriteFrames of sprites built by `to_animated_sprite_3d`.
+const ANIMATION_NAME: &str = "default";
+
+/// Returns the texture filename of a `BMINFO` fragment, which is one frame of a sprite.
+/// A `BMINFO` can list more than one file, but only the first is drawn for the frame.
+/// These are stored in all caps, but the s3d files store filenames in lowercase.
+fn bm_info_texture_filename(bm_info: &BmInfo) -> Option<GString> {
+    bm_info
+        .entries
+        .first()
+        .map(|entry| GString::from(entry.file_name.to_lowercase()))
+}
+
+/// Returns the texture filename of each frame of a `SIMPLESPRITEDEF`, one for each `BMINFO` it references.
+/// Materials and sprites both draw a `SIMPLESPRITEDEF`, so both list their textures through this.
+pub fn iter_simple_sprite_texture_filenames<'a>(
+    wld: &'a WldDoc,
+    sprite_def: &'a SimpleSpriteDef,
+) -> impl Iterator<Item = GString> + 'a {
+    sprite_def
+        .frame_references
+        .iter()
+        .filter_map(|r| wld.get(r))
+        .filter_map(bm_info_texture_filename)
+}
+
+/// Returns the texture filenames of each frame of a `SIMPLESPRITEDEF`.
+pub fn simple_sprite_texture_filenames(wld: &WldDoc, sprite_def: &SimpleSpriteDef) -> PackedStringArray {
+    iter_simple_sprite_texture_filenames(wld, sprite_def).collect()
+}
+
+/// Returns the `SIMPLESPRITEDEF` that a `BLITSPRITEDEF` draws.
+fn blit_sprite_simple_sprite<'a>(wld: &'a WldDoc, blit_sprite_def: &BlitSpriteDef) -> Option<&'a SimpleSpriteDef> {
+    let simple_sprite: &SimpleSprite = wld.get(&blit_sprite_def.reference)?;
+    wld.get(&simple_sprite.reference)
+}
+
+/// Returns the texture filenames of the sprite referenced by a `BLITSPRITE` fragment.
+pub fn blit_sprite_texture_filenames(wld: &WldDoc, reference: &FragmentRef<BlitSprite>) -> PackedStringArray {
+    wld.get(reference)
+        .and_then(|blit_sprite: &BlitSprite| wld.get(&blit_sprite.reference))
+        .and_then(|blit_sprite_def| blit_sprite_simple_sprite(wld, blit_sprite_def))
+        .map(|sprite_def| simple_sprite_texture_filenames(wld, sprite_def))
+        .unwrap_or_default()
+}
+
+/// Looks up each filename in a dictionary of textures, skipping (and reporting) missing textures.
+fn lookup_textures(textures: &Dictionary, filenames: &PackedStringArray) -> Vec<Gd<Texture2D>> {
+    filenames
+        .as_slice()
+        .iter()
+        .filter_map(|filename| {
+            let texture = textures
+                .get(filename.clone())
+                .and_then(|variant| variant.try_to::<Gd<Texture2D>>().ok());
+            if texture.is_none() {
+                godot_error!("Missing texture: {filename}");
+            }
+            texture
+        })
+        .collect()
+}
+
+/// Builds a Sprite3D showing the first texture.
+fn build_sprite_3d(
+    name: &GString,
+    textures: &[Gd<Texture2D>],
+    billboard: BillboardMode,
+    pixel_size: f32,
+) -> Gd<Sprite3D> {
+    let mut sprite = Sprite3D::new_alloc();
+    sprite.set_name(name);
+    sprite.set_billboard_mode(billboard);
+    sprite.set_pixel_size(pixel_size);
+    if let Some(texture) = textures.first() {
+        sprite.set_texture(texture);
+    }
+    sprite
+}
+
+/// Builds an AnimatedSprite3D that loops through the textures, and starts playing when it enters the tree.
+fn build_animated_sprite_3d(
+    name: &GString,
+    textures: &[Gd<Texture2D>],
+    delay: f32,
+    billboard: BillboardMode,
+    pixel_size: f32,
+) -> Gd<AnimatedSprite3D> {
+    let mut frames = SpriteFrames::new_gd();
+    frames.set_animation_loop(ANIMATION_NAME, true);
+    frames.set_animation_speed(ANIMATION_NAME, if delay > 0. { 1. / delay as f64 } else { 0. });
+    for texture in textures {
+        frames.add_frame(ANIMATION_NAME, texture);
+    }
+    let mut sprite = AnimatedSprite3D::new_alloc();
+    sprite.set_name(name);
+    sprite.set_billboard_mode(billboard);
+    sprite.set_pixel_size(pixel_size);
+    sprite.set_sprite_frames(&frames);
+    sprite.set_animation(ANIMATION_NAME);
+    sprite.set_autoplay(ANIMATION_NAME);
+    sprite
+}
+
+/// A wrapper around `BLITSPRITEDEF`, a sprite that always faces the camera.  These are used for particles and sky elements.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DBlitSprite {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, BlitSpriteDef>>,
+}
+
+impl S3DFragment for S3DBlitSprite {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DBlitSprite {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The texture filename of each frame.
+    #[func]
+    pub fn texture_filenames(&self) -> PackedStringArray {
+        self.get_simple_sprite()
+            .map(|sprite_def| simple_sprite_texture_filenames(self.get_wld(), sprite_def))
+            .unwrap_or_default()
+    }
+
+    /// The number of frames.  Sprites with more than one frame are animated.
+    #[func]
+    pub fn frame_count(&self) -> i32 {
+        self.texture_filenames().len() as i32
+    }
+
+    /// The delay between each frame in seconds, or 0 if the sprite is not animated.
+    #[func]
+    pub fn delay(&self) -> f32 {
+        match self.get_simple_sprite().and_then(|sprite_def| sprite_def.sleep) {
+            Some(sleep) => sleep as f32 * 0.001,
+            None => 0.,
+        }
+    }
+
+    /// How the sprite faces the camera, as a `BaseMaterial3D.BillboardMode`.  Blit sprites always face the camera.
+    #[func]
+    pub fn billboard_mode(&self) -> BillboardMode {
+        BillboardMode::ENABLED
+    }
+
+    /// Builds a Sprite3D showing the first frame.
+    /// `textures` maps texture filenames (see `texture_filenames()`) to Texture2Ds, and `pixel_size` is the size of a texel in Godot units.
+    #[func]
+    pub fn to_sprite_3d(&self, textures: Dictionary, pixel_size: f32) -> Gd<Sprite3D> {
+        let textures = lookup_textures(&textures, &self.texture_filenames());
+        build_sprite_3d(&self.name(), &textures, self.billboard_mode(), pixel_size)
+    }
+
+    /// Builds an AnimatedSprite3D that loops through the frames, using the frame delay of the sprite.
+    #[func]
+    pub fn to_animated_sprite_3d(&self, textures: Dictionary, pixel_size: f32) -> Gd<AnimatedSprite3D> {
+        let textures = lookup_textures(&textures, &self.texture_filenames());
+        build_animated_sprite_3d(&self.name(), &textures, self.delay(), self.billboard_mode(), pixel_size)
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DBlitSprite {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &BlitSpriteDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    fn get_simple_sprite(&self) -> Option<&SimpleSpriteDef> {
+        blit_sprite_simple_sprite(self.get_wld(), self.get_frag())
+    }
+
+    /// Creates an S3DBlitSprite from a `BLITSPRITE` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, sprite: &BlitSprite) -> Option<Gd<Self>> {
+        match sprite.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DBlitSprite>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
+
+/// A wrapper around `SPRITE2DDEFINITION`, a flat sprite that is drawn upright and turned towards the camera.
+/// The sprite can have different frames depending on the angle it is viewed from:
+/// its frames are grouped by pitch (the vertical angle) and then by heading (the horizontal angle).
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DSprite2D {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, Sprite2DDef>>,
+}
+
+impl S3DFragment for S3DSprite2D {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DSprite2D {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    /// The size of the sprite, in Godot units.
+    #[func]
+    pub fn size(&self) -> Vector2 {
+        let (x, y) = self.get_frag().scale;
+        Vector2::new(x, y) * WORLD_SCALE
+    }
+
+    /// The center of the sprite, in Godot coordinates.
+    #[func]
+    pub fn center(&self) -> Vector3 {
+        self.get_frag()
+            .center_offset
+            .as_ref()
+            .map(wld_f32_pos_to_gd)
+            .unwrap_or(Vector3::ZERO)
+    }
+
+    /// The number of pitches (vertical view angles) the sprite has frames for.
+    #[func]
+    pub fn pitch_count(&self) -> i32 {
+        self.get_frag().pitches.len() as i32
+    }
+
+    /// The number of headings (horizontal view angles) the given pitch has frames for.
+    #[func]
+    pub fn heading_count(&self, pitch: i32) -> i32 {
+        usize::try_from(pitch)
+            .ok()
+            .and_then(|pitch| self.get_frag().pitches.get(pitch))
+            .map_or(0, |pitch| pitch.headings.len() as i32)
+    }
+
+    /// The texture filename of each frame seen from the given pitch and heading.
+    #[func]
+    pub fn texture_filenames_for_view(&self, pitch: i32, heading: i32) -> PackedStringArray {
+        let wld = self.get_wld();
+        let heading = usize::try_from(pitch)
+            .ok()
+            .and_then(|pitch| self.get_frag().pitches.get(pitch))
+            .zip(usize::try_from(heading).ok())
+            .and_then(|(pitch, heading)| pitch.headings.get(heading));
+        match heading {
+            Some(heading) => heading
+                .frames
+                .iter()
+                .filter_map(|r| wld.get(r))
+                .filter_map(bm_info_texture_filename)
+                .collect(),
+            None => PackedStringArray::new(),
+        }
+    }
+
+    /// The texture filename of each frame seen from the front, i.e. the first pitch and heading.
+    #[func]
+    pub fn texture_filenames(&self) -> PackedStringArray {
+        self.texture_filenames_for_view(0, 0)
+    }
+
+    /// The number of frames seen from the front.  Sprites with more than one frame are animated.
+    #[func]
+    pub fn frame_count(&self) -> i32 {
+        self.texture_filenames().len() as i32
+    }
+
+    /// The delay between each frame in seconds, or 0 if the sprite is not animated.
+    #[func]
+    pub fn delay(&self) -> f32 {
+        match self.get_frag().sleep {
+            Some(sleep) => sleep as f32 * 0.001,
+            None => 0.,
+        }
+    }
+
+    /// How the sprite faces the camera, as a `BaseMaterial3D.BillboardMode`.  2D sprites stay upright.
+    #[func]
+    pub fn billboard_mode(&self) -> BillboardMode {
+        BillboardMode::FIXED_Y
+    }
+
+    /// Builds a Sprite3D showing the first frame seen from the front, scaled to the size of the sprite.
+    /// `textures` maps texture filenames (see `texture_filenames()`) to Texture2Ds.
+    #[func]
+    pub fn to_sprite_3d(&self, textures: Dictionary) -> Gd<Sprite3D> {
+        let textures = lookup_textures(&textures, &self.texture_filenames());
+        let pixel_size = self.pixel_size(&textures);
+        let mut sprite = build_sprite_3d(&self.name(), &textures, self.billboard_mode(), pixel_size);
+        sprite.set_position(self.center());
+        sprite
+    }
+
+    /// Builds an AnimatedSprite3D that loops through the frames seen from the front, scaled to the size of the sprite.
+    #[func]
+    pub fn to_animated_sprite_3d(&self, textures: Dictionary) -> Gd<AnimatedSprite3D> {
+        let textures = lookup_textures(&textures, &self.texture_filenames());
+        let pixel_size = self.pixel_size(&textures);
+        let mut sprite =
+            build_animated_sprite_3d(&self.name(), &textures, self.delay(), self.billboard_mode(), pixel_size);
+        sprite.set_position(self.center());
+        sprite
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DSprite2D {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &Sprite2DDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    /// The size of a texel that makes the first texture as wide as the sprite.
+    fn pixel_size(&self, textures: &[Gd<Texture2D>]) -> f32 {
+        match textures.first() {
+            Some(texture) if texture.get_width() > 0 => self.size().x / texture.get_width() as f32,
+            _ => WORLD_SCALE,
+        }
+    }
+
+    /// Creates an S3DSprite2D from a `SPRITE2D` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, sprite: &Sprite2D) -> Option<Gd<Self>> {
+        match sprite.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DSprite2D>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use godot::classes::RefCounted;
+use godot::prelude::*;
+use libeq_wld::parser::{FragmentRef, Sprite3D, Sprite3DDef, Sprite4D, Sprite4DDef, WldDoc};
+use std::sync::Arc;
+extern crate owning_ref;
+use crate::error::LoadError;
+use crate::wld::{gd_from_frag, gd_from_frag_type};
+use super::{create_fragment_ref, S3DFragment};
+use crate::util::{wld_f32_pos_to_gd, WORLD_SCALE};
+use owning_ref::ArcRef;
+#[cfg(feature = "serde")]
+use super::frag_to_dict;
+
+/// A wrapper around `3DSPRITEDEF`, which defines a camera.
+/// Actors reference these in `S3DActorDef.references()`; they define the view used when the actor is rendered, not visible geometry.
+/// The camera's shape is given as vertices and a BSP tree of polygons.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DSprite3D {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, Sprite3DDef>>,
+}
+
+impl S3DFragment for S3DSprite3D {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DSprite3D {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The center of the sprite, in Godot coordinates.
+    #[func]
+    pub fn center(&self) -> Vector3 {
+        self.get_frag()
+            .center_offset
+            .as_ref()
+            .map(wld_f32_pos_to_gd)
+            .unwrap_or(Vector3::ZERO)
+    }
+
+    /// The bounding radius of the sprite, in Godot units.
+    #[func]
+    pub fn bounding_radius(&self) -> f32 {
+        self.get_frag().bounding_radius.unwrap_or(0.) * WORLD_SCALE
+    }
+
+    /// The vertices of the sprite, in Godot coordinates.
+    #[func]
+    pub fn vertices(&self) -> PackedVector3Array {
+        self.get_frag()
+            .vertices
+            .iter()
+            .map(wld_f32_pos_to_gd)
+            .collect()
+    }
+
+    /// The nodes of the sprite's BSP tree.  Each node is a Dictionary with the keys
+    /// `vertex_indices` (a polygon, as indices into `vertices()`), and `front` and `back`
+    /// (the indices of the child nodes, starting at 1, or 0 for none).
+    #[func]
+    pub fn bsp_nodes(&self) -> Array<Dictionary> {
+        self.get_frag()
+            .bsp_nodes
+            .iter()
+            .map(|node| {
+                let mut dict = Dictionary::new();
+                let vertex_indices: PackedInt32Array =
+                    node.vertex_indexes.iter().map(|&i| i as i32).collect();
+                dict.set("vertex_indices", vertex_indices);
+                dict.set("front", node.front_tree);
+                dict.set("back", node.back_tree);
+                dict
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DSprite3D {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &Sprite3DDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    /// Creates an S3DSprite3D from a `3DSPRITE` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, sprite: &Sprite3D) -> Option<Gd<Self>> {
+        match sprite.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DSprite3D>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
+
+/// A wrapper around `4DSPRITEDEF`, which cycles through a list of other sprites over time.
+/// Actors reference these in `S3DActorDef.references()` as an animated alternative to a single sprite.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct S3DSprite4D {
+    base: Base<RefCounted>,
+    fragment: Option<ArcRef<WldDoc, Sprite4DDef>>,
+}
+
+impl S3DFragment for S3DSprite4D {
+    fn load(&mut self, wld: &Arc<WldDoc>, index: u32) -> Result<(), LoadError> {
+        self.fragment = Some(create_fragment_ref(wld.clone(), index)?);
+        Ok(())
+    }
+}
+
+#[godot_api]
+impl S3DSprite4D {
+    #[func]
+    pub fn name(&self) -> GString {
+        GString::from(
+            self.get_wld()
+                .get_string(self.get_frag().name_reference)
+                .unwrap_or(""),
+        )
+    }
+
+    #[func]
+    pub fn flags(&self) -> u32 {
+        self.get_frag().flags
+    }
+
+    /// The number of frames.
+    #[func]
+    pub fn frame_count(&self) -> u32 {
+        self.get_frag().frame_count
+    }
+
+    /// The frame the sprite starts on.
+    #[func]
+    pub fn current_frame(&self) -> u32 {
+        self.get_frag().current_frame.unwrap_or(0)
+    }
+
+    /// The delay between each frame in seconds, or 0 if the sprite is not animated.
+    #[func]
+    pub fn delay(&self) -> f32 {
+        match self.get_frag().sleep {
+            Some(sleep) => sleep as f32 * 0.001,
+            None => 0.,
+        }
+    }
+
+    /// The sprite shown for each frame, as the Godot class for each referenced fragment (e.g. S3DMesh or S3DSprite3D).
+    #[func]
+    pub fn sprites(&self) -> Array<Variant> {
+        let wld = self.get_wld();
+        self.get_frag()
+            .sprite_fragments
+            .iter()
+            .flatten()
+            .map(|&index| gd_from_frag(wld, index))
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[func]
+    pub fn as_dict(&self) -> Dictionary {
+        let frag = self.get_frag();
+        let wld = self.get_wld();
+        frag_to_dict(wld, frag)
+    }
+}
+
+impl S3DSprite4D {
+    fn get_wld(&self) -> &Arc<WldDoc> {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get WLD reference!")
+            .as_owner()
+    }
+
+    fn get_frag(&self) -> &Sprite4DDef {
+        self.fragment
+            .as_ref()
+            .expect("Failed to get Fragment reference!")
+    }
+
+    /// Creates an S3DSprite4D from a `4DSPRITE` fragment, which only references the definition.
+    pub fn from_reference(wld: &Arc<WldDoc>, sprite: &Sprite4D) -> Option<Gd<Self>> {
+        match sprite.reference {
+            FragmentRef::Index(index, _) => gd_from_frag_type::<S3DSprite4D>(wld, index)
+                .map_err(|e| godot_error!("{e}"))
+                .ok(),
+            FragmentRef::Name(_, _) => None,
+        }
+    }
+}
@@ -1,10 +1,26 @@
 use godot::prelude::*;
 struct EQLoader;
 
+mod animation_names;
 mod archive;
+mod error;
 mod fragments;
 mod loader;
+mod resource_loader;
+mod texture_options;
 mod util;
 mod wld;
 #[gdextension]
-unsafe impl ExtensionLibrary for EQLoader {}
+unsafe impl ExtensionLibrary for EQLoader {
+    fn on_level_init(level: InitLevel) {
+        if level == InitLevel::Scene {
+            resource_loader::register();
+        }
+    }
+
+    fn on_level_deinit(level: InitLevel) {
+        if level == InitLevel::Scene {
+            resource_loader::unregister();
+        }
+    }
+}
@@ -1,20 +1,199 @@
-use crate::archive::EQArchive;
-use godot::classes::{RefCounted, ProjectSettings};
+use crate::archive::{parse_archive, EQArchive, ParsedArchive};
+use crate::error::{EQLoadError, LoadError, LoadErrorKind};
+use crate::texture_options::EQTextureOptions;
+use crate::util::texture::TextureOptions;
+use godot::classes::{FileAccess, RefCounted, ProjectSettings};
 use godot::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Messages sent from the worker threads of `load_archive_async` to the main thread.
+enum AsyncEvent {
+    Progress {
+        filename: String,
+        stage: String,
+        progress: f32,
+    },
+    Loaded {
+        filename: String,
+        parsed: Box<ParsedArchive>,
+    },
+    Failed {
+        filename: String,
+        error: LoadError,
+    },
+}
+
+/// Overall progress reported for each stage of `load_archive_async`, as (start, end) fractions.
+fn stage_range(stage: &str) -> (f32, f32) {
+    match stage {
+        "archive" => (0., 0.1),
+        "textures" => (0.1, 0.8),
+        _ => (0.8, 1.),
+    }
+}
+
 #[derive(GodotClass)]
 #[class(init)]
 pub struct EQArchiveLoader {
     base: Base<RefCounted>,
+    last_error: Option<LoadError>,
+    /// The texture options given to every archive this loader loads
+    texture_options: TextureOptions,
+    /// Events queued by worker threads, waiting to be emitted as signals on the main thread.
+    pending_events: Arc<Mutex<Vec<AsyncEvent>>>,
 }
 
 #[godot_api]
 impl EQArchiveLoader {
+    /// Emitted on the main thread while `load_archive_async` is running.
+    /// `stage` is one of "archive", "textures" or "wlds", and `progress` is the overall progress from 0 to 1.
+    #[signal]
+    fn archive_load_progress(filename: GString, stage: GString, progress: f32);
+
+    /// Emitted on the main thread when `load_archive_async` has finished successfully.
+    #[signal]
+    fn archive_loaded(filename: GString, archive: Gd<EQArchive>);
+
+    /// Emitted on the main thread when `load_archive_async` has failed.
+    #[signal]
+    fn archive_load_failed(filename: GString, error: Gd<EQLoadError>);
+
     /// Load an Everquest .s3d archive, returning an EQArchive object.
+    /// Returns null if the archive could not be opened or parsed - see `last_error()` for details.
     #[func]
-    fn load_archive(&self, filename: GString) -> Gd<EQArchive> {
+    fn load_archive(&mut self, filename: GString) -> Option<Gd<EQArchive>> {
         let filename = String::from(ProjectSettings::singleton().globalize_path(&filename));
         let mut obj: Gd<EQArchive> = Gd::default();
-        obj.bind_mut().load(&filename);
-        obj
+        obj.bind_mut().set_options(self.texture_options);
+        let result = obj.bind_mut().load(&filename);
+        match result {
+            Ok(()) => {
+                self.last_error = None;
+                Some(obj)
+            }
+            Err(e) => {
+                godot_error!("{e}");
+                self.last_error = Some(e);
+                None
+            }
+        }
+    }
+
+    /// Load an Everquest .s3d archive in the background.
+    /// The file is read through FileAccess, then the archive is unpacked, all of its bitmaps are decoded and all of its WLDs
+    /// are parsed on worker threads, so that `get_texture`, `get_image` and `get_wld` don't have to read or parse anything on the resulting EQArchive.
+    /// Mipmaps and compression from the texture options are still applied on the main thread when an image is requested.
+    ///
+    /// Progress is reported with the `archive_load_progress` signal, and the result with
+    /// `archive_loaded` or `archive_load_failed`.  All signals are emitted on the main thread.
+    /// This loader must be kept alive until one of the result signals is emitted.
+    #[func]
+    fn load_archive_async(&mut self, filename: GString) {
+        let filename = filename.to_string();
+        let pending_events = self.pending_events.clone();
+        // Only a Callable crosses to the worker thread, so the loader itself is never referenced off the main thread.
+        let emit_events = self.base().callable("_emit_pending_events");
+        let options = self.texture_options;
+
+        // FileAccess is used from the main thread, so that `res://` paths and archives inside exported packs work.
+        if !FileAccess::file_exists(&filename) {
+            let error = LoadError::new(LoadErrorKind::Io, "Failed to open archive: file not found")
+                .with_filename(&filename);
+            push_event(&pending_events, &emit_events, AsyncEvent::Failed { filename, error });
+            return;
+        }
+        let data = FileAccess::get_file_as_bytes(&filename).to_vec();
+
+        thread::spawn(move || {
+            let push = |event: AsyncEvent| push_event(&pending_events, &emit_events, event);
+            let result = parse_archive(&filename, data, options, |stage, progress| {
+                let (start, end) = stage_range(stage);
+                push(AsyncEvent::Progress {
+                    filename: filename.clone(),
+                    stage: String::from(stage),
+                    progress: start + (end - start) * progress,
+                });
+            });
+            push(match result {
+                Ok(parsed) => AsyncEvent::Loaded {
+                    filename: filename.clone(),
+                    parsed: Box::new(parsed),
+                },
+                Err(error) => AsyncEvent::Failed {
+                    filename: filename.clone(),
+                    error,
+                },
+            });
+        });
+    }
+
+    /// Sets the texture options given to every archive loaded by this loader.
+    /// For `load_archive_async`, only the decoding of the bitmaps (including `key_color_alpha`) is done on the worker threads.
+    /// Mipmap generation and compression are applied lazily, on the main thread, the first time each image is requested.
+    #[func]
+    fn set_texture_options(&mut self, options: Gd<EQTextureOptions>) {
+        self.texture_options = options.bind().options();
+    }
+
+    /// Returns the error that caused the most recent call to `load_archive` to return null, or null if it succeeded.
+    #[func]
+    fn last_error(&self) -> Option<Gd<EQLoadError>> {
+        self.last_error.as_ref().map(LoadError::to_gd)
+    }
+
+    /// Emits the signals for all events queued by worker threads.
+    /// This is called deferred, on the main thread, and should not be called directly.
+    #[func]
+    fn _emit_pending_events(&mut self) {
+        let events = std::mem::take(&mut *self.pending_events.lock().unwrap());
+        for event in events {
+            match event {
+                AsyncEvent::Progress {
+                    filename,
+                    stage,
+                    progress,
+                } => {
+                    self.base_mut().emit_signal(
+                        "archive_load_progress",
+                        &[
+                            GString::from(filename).to_variant(),
+                            GString::from(stage).to_variant(),
+                            progress.to_variant(),
+                        ],
+                    );
+                }
+                AsyncEvent::Loaded { filename, parsed } => {
+                    let mut archive: Gd<EQArchive> = Gd::default();
+                    archive.bind_mut().load_parsed(&filename, *parsed);
+                    self.base_mut().emit_signal(
+                        "archive_loaded",
+                        &[GString::from(filename).to_variant(), archive.to_variant()],
+                    );
+                }
+                AsyncEvent::Failed { filename, error } => {
+                    godot_error!("{error}");
+                    let gd_error = error.to_gd();
+                    self.last_error = Some(error);
+                    self.base_mut().emit_signal(
+                        "archive_load_failed",
+                        &[GString::from(filename).to_variant(), gd_error.to_variant()],
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Queues an event from a worker thread, and asks the main thread to emit it through `emit_events`.
+/// The main thread is only notified when the queue was empty, so bursts of progress events are emitted together.
+fn push_event(pending_events: &Mutex<Vec<AsyncEvent>>, emit_events: &Callable, event: AsyncEvent) {
+    let mut events = pending_events.lock().unwrap();
+    let was_empty = events.is_empty();
+    events.push(event);
+    drop(events);
+    if was_empty {
+        // Deferred calls are made on the main thread, and skipped if the loader has been freed in the meantime.
+        emit_events.call_deferred(&[]);
     }
 }
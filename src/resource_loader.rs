@@ -0,0 +1,169 @@
+use crate::archive::EQArchive;
+use godot::classes::resource_loader::CacheMode;
+use godot::classes::{FileAccess, IResourceFormatLoader, ResourceFormatLoader, ResourceLoader};
+use godot::global::Error;
+use godot::prelude::*;
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// File extensions of archives handled by the loader.
+const ARCHIVE_EXTENSIONS: [&str; 1] = ["s3d"];
+
+/// Separates the archive path from the path of a file within the archive,
+/// e.g. `res://eq_data/rivervale.s3d#palette.bmp`
+/// This is not `::`, which Godot already uses for sub-resources (e.g. `res://scene.tscn::Resource_id`).
+const INNER_PATH_SEPARATOR: &str = "#";
+
+thread_local! {
+    /// The registered loader, kept so it can be removed again when the extension is unloaded.
+    static REGISTERED_LOADER: RefCell<Option<Gd<EQResourceFormatLoader>>> = const { RefCell::new(None) };
+}
+
+/// Register the loader with Godot's ResourceLoader.  Called when the extension is initialized.
+pub fn register() {
+    let loader = EQResourceFormatLoader::new_gd();
+    ResourceLoader::singleton().add_resource_format_loader(&loader);
+    REGISTERED_LOADER.with(|cell| *cell.borrow_mut() = Some(loader));
+}
+
+/// Remove the loader from Godot's ResourceLoader.  Called when the extension is deinitialized.
+pub fn unregister() {
+    if let Some(loader) = REGISTERED_LOADER.with(|cell| cell.borrow_mut().take()) {
+        ResourceLoader::singleton().remove_resource_format_loader(&loader);
+    }
+}
+
+/// Splits a path into the archive path and, if present, the filename within the archive.
+fn split_path(path: &str) -> (&str, Option<&str>) {
+    match path.split_once(INNER_PATH_SEPARATOR) {
+        Some((archive_path, inner_path)) => (archive_path, Some(inner_path)),
+        None => (path, None),
+    }
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+fn is_archive_path(path: &str) -> bool {
+    ARCHIVE_EXTENSIONS.contains(&extension(path).as_str())
+}
+
+/// Returns the Godot class that will be loaded for the given path, or None if the path is not handled.
+fn resource_type(path: &str) -> Option<&'static str> {
+    let (archive_path, inner_path) = split_path(path);
+    if !is_archive_path(archive_path) {
+        return None;
+    }
+    match inner_path {
+        None => Some("EQArchive"),
+        Some(inner_path) => match extension(inner_path).as_str() {
+            "bmp" | "dds" => Some("ImageTexture"),
+            "wav" => Some("AudioStreamWAV"),
+            "wld" => Some("S3DWld"),
+            _ => None,
+        },
+    }
+}
+
+/// Allows archives and their contents to be loaded with Godot's `load()`, and cached by the ResourceLoader.
+///
+/// `load("res://eq_data/rivervale.s3d")` returns an `EQArchive`.
+/// `load("res://eq_data/rivervale.s3d#palette.bmp")` returns the file within the archive
+/// as an `ImageTexture`, `AudioStreamWAV` or `S3DWld`, depending on its extension.
+#[derive(GodotClass)]
+#[class(init, tool, base=ResourceFormatLoader)]
+pub struct EQResourceFormatLoader {
+    base: Base<ResourceFormatLoader>,
+}
+
+#[godot_api]
+impl IResourceFormatLoader for EQResourceFormatLoader {
+    fn get_recognized_extensions(&self) -> PackedStringArray {
+        ARCHIVE_EXTENSIONS.iter().map(|ext| GString::from(*ext)).collect()
+    }
+
+    fn recognize_path(&self, path: GString, _type: StringName) -> bool {
+        resource_type(&path.to_string()).is_some()
+    }
+
+    fn handles_type(&self, type_: StringName) -> bool {
+        matches!(
+            type_.to_string().as_str(),
+            "EQArchive" | "S3DWld" | "ImageTexture" | "Texture2D" | "AudioStreamWAV" | "AudioStream"
+        )
+    }
+
+    fn get_resource_type(&self, path: GString) -> GString {
+        GString::from(resource_type(&path.to_string()).unwrap_or(""))
+    }
+
+    fn exists(&self, path: GString) -> bool {
+        let path = path.to_string();
+        let (archive_path, _) = split_path(&path);
+        FileAccess::file_exists(archive_path)
+    }
+
+    fn load(
+        &self,
+        path: GString,
+        _original_path: GString,
+        _use_sub_threads: bool,
+        _cache_mode: i32,
+    ) -> Variant {
+        let path = path.to_string();
+        match split_path(&path) {
+            (archive_path, None) => load_archive(archive_path),
+            (archive_path, Some(inner_path)) => load_inner(archive_path, inner_path),
+        }
+    }
+}
+
+/// Reads an archive through FileAccess, so that archives inside exported packs work too.
+fn load_archive(path: &str) -> Variant {
+    if !FileAccess::file_exists(path) {
+        return Error::ERR_FILE_NOT_FOUND.to_variant();
+    }
+    let data = FileAccess::get_file_as_bytes(path).to_vec();
+    let mut archive: Gd<EQArchive> = Gd::default();
+    let result = archive.bind_mut().load_bytes(path, data);
+    match result {
+        Ok(()) => archive.to_variant(),
+        Err(e) => {
+            godot_error!("{e}");
+            Error::ERR_FILE_CORRUPT.to_variant()
+        }
+    }
+}
+
+/// Loads a file from within an archive.
+/// The archive itself is loaded through the ResourceLoader with `CACHE_MODE_REUSE`, so it is only read and parsed
+/// the first time - every later file from the same archive gets the cached EQArchive.
+fn load_inner(archive_path: &str, inner_path: &str) -> Variant {
+    let archive = match ResourceLoader::singleton()
+        .load_ex(archive_path)
+        .cache_mode(CacheMode::REUSE)
+        .done()
+        .and_then(|resource| resource.try_cast::<EQArchive>().ok())
+    {
+        Some(archive) => archive,
+        None => return Error::ERR_FILE_CANT_OPEN.to_variant(),
+    };
+    let filename = GString::from(inner_path);
+    let resource = match extension(inner_path).as_str() {
+        "bmp" | "dds" => archive.bind().get_texture(filename).map(|tex| tex.to_variant()),
+        "wav" => archive.bind().get_sound(filename).map(|sound| sound.to_variant()),
+        "wld" => {
+            let mut archive = archive;
+            let wld = archive.bind_mut().get_wld(filename);
+            wld.map(|wld| wld.to_variant())
+        }
+        _ => return Error::ERR_FILE_UNRECOGNIZED.to_variant(),
+    };
+    resource.unwrap_or_else(|| Error::ERR_FILE_CORRUPT.to_variant())
+}
@@ -0,0 +1,44 @@
+use crate::util::texture::TextureOptions;
+use godot::classes::base_material_3d::TextureFilter;
+use godot::classes::RefCounted;
+use godot::prelude::*;
+
+/// Options that control how images are decoded from an archive.
+///
+/// Set them for a whole archive with `EQArchive.set_texture_options`, for every archive a loader loads with
+/// `EQArchiveLoader.set_texture_options`, or for a single image with `EQArchive.get_image_with_options`.
+#[derive(GodotClass)]
+#[class(init)]
+pub struct EQTextureOptions {
+    base: Base<RefCounted>,
+    /// Convert palettized BMPs to RGBA8, where pixels using the key color (the first palette entry) have an alpha of 0.
+    /// This allows masked materials to use plain alpha scissor instead of comparing colors in a shader.
+    #[var]
+    key_color_alpha: bool,
+    /// Generate mipmaps for images that don't have them, which stops distant geometry from shimmering.
+    /// When `key_color_alpha` is also set, the colors of cutout pixels are fixed first so they don't bleed into the edges.
+    /// Like compression, this happens on the main thread when the image is requested.
+    #[var]
+    generate_mipmaps: bool,
+    /// Compress images to S3TC, or ETC2 if the renderer does not support S3TC.
+    /// Compression is slow, and happens on the main thread the first time each image is requested from the archive,
+    /// even for archives loaded with `EQArchiveLoader.load_archive_async`.
+    #[var]
+    compress: bool,
+    /// The filter that `S3DMaterial.to_material` uses for textures loaded with these options.
+    /// It is stored as "texture_filter" metadata on each texture.
+    #[var]
+    #[init(val = TextureFilter::LINEAR_WITH_MIPMAPS)]
+    filter: TextureFilter,
+}
+
+impl EQTextureOptions {
+    pub fn options(&self) -> TextureOptions {
+        TextureOptions {
+            key_color_alpha: self.key_color_alpha,
+            generate_mipmaps: self.generate_mipmaps,
+            compress: self.compress,
+            filter: self.filter,
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+/// A descriptive name for the animations with a given prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationName {
+    /// A broad grouping of the animation: "combat", "damage", "locomotion", "passive" or "social".
+    pub category: String,
+    /// A readable name for the animation, e.g. "walk" for L01.
+    pub alias: String,
+    /// True if the client plays the animation in a loop.
+    pub looping: bool,
+}
+
+/// The mapping from the raw animation prefixes used in track names (`C01`, `D02`, `L01`...) to descriptive names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationNames {
+    entries: HashMap<String, AnimationName>,
+}
+
+/// The animations the client knows about, as (prefix, alias, looping).
+/// Sources: LanternExtractor and the EQEmu animation enums.  Not every model has every animation.
+const DEFAULT_ANIMATION_NAMES: [(&str, &str, bool); 58] = [
+    ("C01", "kick", false),
+    ("C02", "pierce", false),
+    ("C03", "slash_2h", false),
+    ("C04", "blunt_2h", false),
+    ("C05", "throw", false),
+    ("C06", "slash_offhand", false),
+    ("C07", "bash", false),
+    ("C08", "slash_1h", false),
+    ("C09", "archery", false),
+    ("C10", "swim_attack", false),
+    ("C11", "round_kick", false),
+    ("D01", "hit", false),
+    ("D02", "hit_heavy", false),
+    ("D03", "hit_trap", false),
+    ("D04", "drown", false),
+    ("D05", "die", false),
+    ("L01", "walk", true),
+    ("L02", "run", true),
+    ("L03", "jump_running", false),
+    ("L04", "jump", false),
+    ("L05", "fall", true),
+    ("L06", "crouch_walk", true),
+    ("L07", "climb", true),
+    ("L08", "crouch", false),
+    ("L09", "swim_idle", true),
+    ("O01", "idle_alt", false),
+    ("P01", "idle", true),
+    ("P02", "sit_down", false),
+    ("P03", "shuffle", false),
+    ("P06", "swim", true),
+    ("P07", "sit", false),
+    ("P08", "stand", false),
+    ("S01", "cheer", false),
+    ("S02", "mourn", false),
+    ("S03", "wave", false),
+    ("S04", "rude", false),
+    ("S05", "yawn", false),
+    ("S06", "nod", false),
+    ("S07", "amazed", false),
+    ("S08", "plead", false),
+    ("S09", "clap", false),
+    ("S10", "distress", false),
+    ("S11", "blush", false),
+    ("S12", "chuckle", false),
+    ("S13", "burp", false),
+    ("S14", "duck", false),
+    ("S15", "look_around", false),
+    ("S16", "dance", false),
+    ("S17", "blink", false),
+    ("S18", "glare", false),
+    ("S19", "drool", false),
+    ("S20", "kneel", false),
+    ("S21", "laugh", false),
+    ("S22", "point", false),
+    ("S23", "shrug", false),
+    ("S24", "ready", false),
+    ("S25", "salute", false),
+    ("S26", "shiver", false),
+];
+
+/// The length of the animation prefix at the start of track names.
+const PREFIX_LEN: usize = 3;
+
+/// The category of a prefix that is not in the table, from its first letter.
+/// Spell casting animations (T) are counted as combat.
+fn category_from_letter(prefix: &str) -> &'static str {
+    match prefix.chars().next() {
+        Some('C') | Some('T') => "combat",
+        Some('D') => "damage",
+        Some('L') => "locomotion",
+        Some('O') | Some('P') => "passive",
+        Some('S') => "social",
+        _ => "",
+    }
+}
+
+impl Default for AnimationNames {
+    fn default() -> Self {
+        let entries = DEFAULT_ANIMATION_NAMES
+            .iter()
+            .map(|&(prefix, alias, looping)| {
+                (
+                    String::from(prefix),
+                    AnimationName {
+                        category: String::from(category_from_letter(prefix)),
+                        alias: String::from(alias),
+                        looping,
+                    },
+                )
+            })
+            .collect();
+        Self { entries }
+    }
+}
+
+impl AnimationNames {
+    /// A table with no entries, where every animation only has its raw name.
+    pub fn empty() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, prefix: &str, name: AnimationName) {
+        self.entries.insert(prefix.to_uppercase(), name);
+    }
+
+    pub fn remove(&mut self, prefix: &str) {
+        self.entries.remove(&prefix.to_uppercase());
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &AnimationName)> {
+        self.entries.iter()
+    }
+
+    /// Looks up the name of an animation from its raw name, which may have more after the prefix (e.g. "C05A").
+    pub fn get(&self, animation_name: &str) -> Option<&AnimationName> {
+        let prefix = animation_name.get(..PREFIX_LEN)?;
+        self.entries.get(&prefix.to_uppercase())
+    }
+
+    /// The category of an animation, from the table or else from the first letter of its prefix, in any case.
+    pub fn category(&self, animation_name: &str) -> String {
+        match self.get(animation_name) {
+            Some(name) => name.category.clone(),
+            None => String::from(category_from_letter(&animation_name.to_uppercase())),
+        }
+    }
+
+    /// The alias of an animation, keeping anything after the prefix (so "C05A" becomes "throw_A"), or None if it has none.
+    pub fn alias(&self, animation_name: &str) -> Option<String> {
+        let name = self.get(animation_name)?;
+        if name.alias.is_empty() {
+            return None;
+        }
+        match &animation_name[PREFIX_LEN..] {
+            "" => Some(name.alias.clone()),
+            rest => Some(format!("{}_{}", name.alias, rest)),
+        }
+    }
+
+    pub fn is_looping(&self, animation_name: &str) -> bool {
+        self.get(animation_name).is_some_and(|name| name.looping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_keep_the_suffix() {
+        let names = AnimationNames::default();
+        assert_eq!(names.alias("L01").as_deref(), Some("walk"));
+        assert_eq!(names.alias("C05A").as_deref(), Some("throw_A"));
+        assert_eq!(names.alias("Z99"), None);
+        assert_eq!(names.alias("L0"), None);
+    }
+
+    #[test]
+    fn prefixes_are_looked_up_in_uppercase() {
+        let names = AnimationNames::default();
+        assert_eq!(names.alias("l01").as_deref(), Some("walk"));
+        assert!(names.is_looping("p01"));
+        assert!(!names.is_looping("c01"));
+    }
+
+    #[test]
+    fn unknown_prefixes_get_a_category_from_their_letter() {
+        let names = AnimationNames::default();
+        assert_eq!(names.category("C12"), "combat");
+        assert_eq!(names.category("c12"), "combat");
+        assert_eq!(names.category("T01"), "combat");
+        assert_eq!(names.category("x01"), "");
+        assert_eq!(names.category(""), "");
+    }
+
+    #[test]
+    fn entries_can_be_replaced_and_removed() {
+        let mut names = AnimationNames::default();
+        names.set(
+            "l01",
+            AnimationName {
+                category: String::from("locomotion"),
+                alias: String::from("stroll"),
+                looping: false,
+            },
+        );
+        assert_eq!(names.alias("L01").as_deref(), Some("stroll"));
+        assert!(!names.is_looping("L01"));
+        names.remove("L01");
+        assert_eq!(names.alias("L01"), None);
+        assert_eq!(names.category("L01"), "locomotion");
+    }
+
+    #[test]
+    fn empty_aliases_are_none() {
+        let mut names = AnimationNames::empty();
+        names.set(
+            "S01",
+            AnimationName {
+                category: String::from("social"),
+                alias: String::new(),
+                looping: false,
+            },
+        );
+        assert_eq!(names.alias("S01"), None);
+        assert_eq!(names.category("S01"), "social");
+    }
+}
@@ -0,0 +1,90 @@
+use godot::classes::ConcavePolygonShape3D;
+use godot::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+
+/// Triangles with less area than this (in square Godot units) are dropped as degenerate.
+const MIN_TRIANGLE_AREA: f32 = 1e-6;
+
+/// Vertices closer than this (in Godot units) are treated as the same vertex when finding duplicate triangles.
+const VERTEX_PRECISION: f32 = 1e-3;
+
+type VertexKey = (i32, i32, i32);
+
+fn vertex_key(v: Vector3) -> VertexKey {
+    (
+        (v.x / VERTEX_PRECISION).round() as i32,
+        (v.y / VERTEX_PRECISION).round() as i32,
+        (v.z / VERTEX_PRECISION).round() as i32,
+    )
+}
+
+/// A key that is the same for any rotation of the triangle's vertices, but not for the reversed winding.
+/// Two-sided walls are made of two faces with opposite windings, and both are needed for collision.
+fn triangle_key(triangle: &[Vector3]) -> [VertexKey; 3] {
+    let keys = [vertex_key(triangle[0]), vertex_key(triangle[1]), vertex_key(triangle[2])];
+    let first = (0..3).min_by_key(|&i| keys[i]).unwrap_or(0);
+    [keys[first], keys[(first + 1) % 3], keys[(first + 2) % 3]]
+}
+
+fn is_degenerate(triangle: &[Vector3]) -> bool {
+    let cross = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+    cross.length() * 0.5 < MIN_TRIANGLE_AREA
+}
+
+/// Collects triangles from many meshes into concave collision shapes, dropping degenerate and duplicate triangles.
+/// Triangles are grouped into square chunks on the XZ plane by their centroid, so that a large zone
+/// becomes several smaller shapes.  A `chunk_size` of 0 puts every triangle in a single shape.
+pub struct CollisionBuilder {
+    chunk_size: f32,
+    seen: HashSet<[VertexKey; 3]>,
+    chunks: BTreeMap<(i32, i32), Vec<Vector3>>,
+}
+
+impl CollisionBuilder {
+    pub fn new(chunk_size: f32) -> Self {
+        Self {
+            chunk_size: chunk_size.max(0.),
+            seen: HashSet::new(),
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a triangle soup, where every 3 vertices form a triangle (the format of `S3DMesh.collision_vertices()`).
+    pub fn add_triangles(&mut self, vertices: &[Vector3]) {
+        for triangle in vertices.chunks_exact(3) {
+            if is_degenerate(triangle) || !self.seen.insert(triangle_key(triangle)) {
+                continue;
+            }
+            let cell = self.cell(triangle);
+            self.chunks.entry(cell).or_default().extend_from_slice(triangle);
+        }
+    }
+
+    fn cell(&self, triangle: &[Vector3]) -> (i32, i32) {
+        if self.chunk_size == 0. {
+            return (0, 0);
+        }
+        let centroid = (triangle[0] + triangle[1] + triangle[2]) / 3.;
+        (
+            (centroid.x / self.chunk_size).floor() as i32,
+            (centroid.z / self.chunk_size).floor() as i32,
+        )
+    }
+
+    /// The remaining triangles of every chunk, in a stable order.
+    pub fn into_triangles(self) -> Vec<Vec<Vector3>> {
+        self.chunks.into_values().collect()
+    }
+
+    /// Builds one ConcavePolygonShape3D per chunk that has triangles.
+    pub fn build(self) -> Vec<Gd<ConcavePolygonShape3D>> {
+        self.into_triangles()
+            .into_iter()
+            .map(|triangles| {
+                let mut shape = ConcavePolygonShape3D::new_gd();
+                shape.set_faces(&triangles.into_iter().collect::<PackedVector3Array>());
+                shape
+            })
+            .collect()
+    }
+}
@@ -1,3 +1,5 @@
+pub mod animation;
+pub mod collision;
 pub mod sound;
 pub mod texture;
 use godot::prelude::*;
@@ -11,6 +13,12 @@ pub fn wld_f32_pos_to_gd(tup: &(f32, f32, f32)) -> Vector3 {
     Vector3::new(tup.0 * -1., tup.2, tup.1) * WORLD_SCALE
 }
 
+/// Convert a position in Godot coordinates back into EQ coordinates - the inverse of `wld_f32_pos_to_gd`
+pub fn gd_pos_to_wld(v: Vector3) -> (f32, f32, f32) {
+    let v = v / WORLD_SCALE;
+    (v.x * -1., v.z, v.y)
+}
+
 /// Convert a int16 position value expressed in EQ coordinates into Godot coordinates
 pub fn wld_i16_pos_to_gd(p: &(i16, i16, i16), scale: f32) -> Vector3 {
     Vector3::new(
@@ -1,48 +1,190 @@
-use godot::classes::image::Format;
-use godot::classes::{Image, ImageTexture};
+use godot::classes::base_material_3d::TextureFilter;
+use godot::classes::image::{AlphaMode, CompressMode, Format};
+use godot::classes::{Image, ImageTexture, RenderingServer, Texture2DArray};
 use godot::prelude::*;
 use image::codecs::bmp::BmpDecoder;
-use image::DynamicImage;
+use image::{DynamicImage, ImageDecoder};
+use std::ffi::OsStr;
 use std::io::Cursor;
+use std::path::Path;
 #[cfg(feature = "dds")]
 use image::codecs::dds::DdsDecoder;
 
+/// The size of the "DDS " magic number plus the DDS_HEADER structure
 #[cfg(feature = "dds")]
-pub fn image_from_dds(dds_data: Vec<u8>) -> Result<Gd<Image>, &'static str> {
+const DDS_HEADER_SIZE: usize = 128;
+/// DDS_PIXELFORMAT flag indicating that the four-character code is valid
+#[cfg(feature = "dds")]
+const DDPF_FOURCC: u32 = 0x4;
+
+#[cfg(feature = "dds")]
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// The size in bytes of each level of a full block-compressed mip chain, from the full size down to 1x1.
+#[cfg(feature = "dds")]
+fn dds_level_sizes(width: u32, height: u32, block_size: usize) -> Vec<usize> {
+    let (mut w, mut h) = (width, height);
+    let mut level_sizes = vec![];
+    loop {
+        level_sizes.push(w.div_ceil(4) as usize * h.div_ceil(4) as usize * block_size);
+        if w == 1 && h == 1 {
+            break;
+        }
+        w = (w >> 1).max(1);
+        h = (h >> 1).max(1);
+    }
+    level_sizes
+}
+
+/// Decodes a DXT1/3/5 compressed DDS into Godot's matching block-compressed format, keeping the mipmaps.
+/// Returns None for uncompressed or unsupported DDS variants.
+#[cfg(feature = "dds")]
+fn decode_compressed_dds(dds_data: &[u8]) -> Option<DecodedImage> {
+    if dds_data.get(0..4)? != b"DDS " {
+        return None;
+    }
+    let height = read_u32(dds_data, 12)?;
+    let width = read_u32(dds_data, 16)?;
+    let mip_count = read_u32(dds_data, 28)?.max(1);
+    if width == 0 || height == 0 || read_u32(dds_data, 80)? & DDPF_FOURCC == 0 {
+        return None;
+    }
+    let (format, block_size) = match dds_data.get(84..88)? {
+        b"DXT1" => (Format::DXT1, 8),
+        b"DXT3" => (Format::DXT3, 16),
+        b"DXT5" => (Format::DXT5, 16),
+        _ => return None,
+    };
+
+    // Godot's mip chain for block-compressed formats goes down to 1x1, like DDS files, with each level padded to whole 4x4 blocks.
+    // Godot needs the complete chain, so if the DDS has fewer levels than that, only the first level is kept.
+    let level_sizes = dds_level_sizes(width, height, block_size);
+    let mipmaps = mip_count as usize >= level_sizes.len() && level_sizes.len() > 1;
+    let size: usize = if mipmaps {
+        level_sizes.iter().sum()
+    } else {
+        level_sizes[0]
+    };
+    let data = dds_data
+        .get(DDS_HEADER_SIZE..DDS_HEADER_SIZE + size)?
+        .to_vec();
+    Some(DecodedImage {
+        width,
+        height,
+        format,
+        data,
+        key_color: None,
+        mipmaps,
+    })
+}
+
+/// Decodes the bytes representing a DDS file, without creating any Godot objects.
+/// DXT1/3/5 images are kept in their compressed format, with mipmaps.  Other variants are converted to RGBA8.
+#[cfg(feature = "dds")]
+pub fn decode_dds(dds_data: Vec<u8>) -> Result<DecodedImage, &'static str> {
+    if let Some(decoded) = decode_compressed_dds(&dds_data) {
+        return Ok(decoded);
+    }
     let mut file = Cursor::new(dds_data);
     let decoder = DdsDecoder::new(&mut file).map_err(|_| "Invalid DDS data!")?;
     let dds = DynamicImage::from_decoder(decoder).map_err(|_| "Failed to decode DDS data!")?;
-    // FIXME: There is no API for loading DDS data directly into Godot.
-    // I am converting to 8-bit RGBA as a temporary solution (which of course eliminates mipmaps and whatever compression is used)
+    // Unsupported variants are converted to 8-bit RGBA (which of course eliminates mipmaps and whatever compression is used)
     let buffer = dds.into_rgba8();
-    let image = Image::create_from_data(
-        buffer.width() as i32,
-        buffer.height() as i32,
-        false,
-        Format::RGBA8,
-        &PackedByteArray::from(&buffer.into_raw()[..]),
-    ).ok_or_else(|| "Failed to create Godot Image from Image")?;
-    Ok(image)
+    Ok(DecodedImage {
+        width: buffer.width(),
+        height: buffer.height(),
+        format: Format::RGBA8,
+        data: buffer.into_raw(),
+        key_color: None,
+        mipmaps: false,
+    })
+}
+
+/// Returns true if the file is an image that can be decoded by `decode_image`
+pub fn is_image_filename(filename: &str) -> bool {
+    filename.ends_with(".bmp") || (cfg!(feature = "dds") && filename.ends_with(".dds"))
+}
+
+/// Decodes an image from an archive, choosing the decoder from the file extension.
+pub fn decode_image(
+    filename: &str,
+    data: Vec<u8>,
+    options: &TextureOptions,
+) -> Result<DecodedImage, &'static str> {
+    match Path::new(filename).extension().and_then(OsStr::to_str).unwrap_or("") {
+        "bmp" => decode_bmp(data, options),
+        #[cfg(feature = "dds")]
+        "dds" => decode_dds(data),
+        _ => Err("Unsupported image format"),
+    }
+}
+
+/// Options that control how images are decoded from an archive.
+/// See `EQTextureOptions` for the Godot-facing equivalent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureOptions {
+    /// Convert palettized BMPs to RGBA8, where pixels using the key color (the first palette entry) have an alpha of 0.
+    pub key_color_alpha: bool,
+    /// Generate mipmaps for images that don't already have them.
+    pub generate_mipmaps: bool,
+    /// Compress images into a VRAM format supported by the renderer.
+    pub compress: bool,
+    /// The filter hint stored as metadata on textures, used by `S3DMaterial.to_material`.
+    pub filter: TextureFilter,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        TextureOptions {
+            key_color_alpha: false,
+            generate_mipmaps: false,
+            compress: false,
+            filter: TextureFilter::LINEAR_WITH_MIPMAPS,
+        }
+    }
+}
+
+impl TextureOptions {
+    /// Returns true if images decoded with the other options have the same pixel data as with these options.
+    /// Mipmaps, compression and filtering are applied after decoding, so they do not matter.
+    pub fn decodes_like(&self, other: &TextureOptions) -> bool {
+        self.key_color_alpha == other.key_color_alpha
+    }
 }
 
-/// Creates an Image from the bytes representing a BMP file.
+/// A decoded image that has not yet been turned into a Godot Image.
+/// Unlike Godot objects, this can be created on a worker thread and handed to the main thread.
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: Format,
+    pub data: Vec<u8>,
+    /// The first color of the BMP palette, if the BMP is palettized.
+    pub key_color: Option<[u8; 3]>,
+    /// True if `data` contains the full mip chain after the first level.
+    pub mipmaps: bool,
+}
+
+/// Decodes the bytes representing a BMP file, without creating any Godot objects.
 /// The image is converted to RGB8 if it is a format that is unsupported in Godot.
-/// The "key color" for cutout transparency is the first color in the BMP palette.  This is stored as metadata in the Godot texture to be used later.
-pub fn image_from_bmp(bmp_data: Vec<u8>) -> Result<Gd<Image>, &'static str> {
+pub fn decode_bmp(bmp_data: Vec<u8>, options: &TextureOptions) -> Result<DecodedImage, &'static str> {
     let mut file = Cursor::new(bmp_data);
-    let decoder = BmpDecoder::new(&mut file).map_err(|_| "Invalid bitmap data!")?;
+    let mut decoder = BmpDecoder::new(&mut file).map_err(|_| "Invalid bitmap data!")?;
     // NOTE: It is not necessary to get the BMP palette except for images with cutout transparency.
     // Possibly this operation should be optional if it is expensive, but it doesn't seem to be.
-    let key_color = match decoder.get_palette() {
-        Some(palette) => Variant::from(Color::from_rgb(
-            palette[0][0] as f32 / 255.0,
-            palette[0][1] as f32 / 255.0,
-            palette[0][2] as f32 / 255.0,
-        )),
-        None => Variant::nil(),
-    };
+    let palette: Option<Vec<[u8; 3]>> = decoder.get_palette().map(|palette| palette.to_vec());
+    let key_color = palette.as_ref().and_then(|palette| palette.first().copied());
+    if options.key_color_alpha {
+        if let Some(palette) = palette {
+            decoder.set_indexed_color(true);
+            return decode_keyed_bmp(decoder, &palette);
+        }
+    }
     let bmp = DynamicImage::from_decoder(decoder).map_err(|_| "Failed to decode BMP data!")?;
-    let (width, height, image_format, buffer) = match bmp {
+    let (width, height, format, data) = match bmp {
         DynamicImage::ImageRgb8(buffer) => (
             buffer.width(),
             buffer.height(),
@@ -69,27 +211,162 @@ pub fn image_from_bmp(bmp_data: Vec<u8>) -> Result<Gd<Image>, &'static str> {
             )
         }
     };
+    Ok(DecodedImage {
+        width,
+        height,
+        format,
+        data,
+        key_color,
+        mipmaps: false,
+    })
+}
+
+/// Decodes a palettized BMP to RGBA8, using the palette indices rather than the colors to find the key color.
+/// This means the cutout is exact, unlike comparing colors in a shader.
+fn decode_keyed_bmp(
+    decoder: BmpDecoder<&mut Cursor<Vec<u8>>>,
+    palette: &[[u8; 3]],
+) -> Result<DecodedImage, &'static str> {
+    let (width, height) = decoder.dimensions();
+    let mut indices = vec![0; decoder.total_bytes() as usize];
+    decoder
+        .read_image(&mut indices)
+        .map_err(|_| "Failed to decode BMP data!")?;
+    let data = indices
+        .iter()
+        .flat_map(|&index| {
+            let [r, g, b] = palette.get(index as usize).copied().unwrap_or_default();
+            let alpha = if index == 0 { 0 } else { 255 };
+            [r, g, b, alpha]
+        })
+        .collect();
+    Ok(DecodedImage {
+        width,
+        height,
+        format: Format::RGBA8,
+        data,
+        key_color: palette.first().copied(),
+        mipmaps: false,
+    })
+}
+
+/// Creates a Godot Image from a decoded image.
+/// The "key color" for cutout transparency is stored as metadata in the Godot image to be used later.
+pub fn image_from_decoded(decoded: &DecodedImage) -> Result<Gd<Image>, &'static str> {
     let mut image = Image::create_from_data(
-        width as i32,
-        height as i32,
-        false,
-        image_format,
-        &PackedByteArray::from(&buffer[..]),
+        decoded.width as i32,
+        decoded.height as i32,
+        decoded.mipmaps,
+        decoded.format,
+        &PackedByteArray::from(&decoded.data[..]),
     )
     .ok_or_else(|| "Failed to create Godot Image from Image")?;
+    let key_color = match decoded.key_color {
+        Some(color) => Variant::from(Color::from_rgb(
+            color[0] as f32 / 255.0,
+            color[1] as f32 / 255.0,
+            color[2] as f32 / 255.0,
+        )),
+        None => Variant::nil(),
+    };
     image.set_meta(&StringName::from("key_color"), &key_color);
     Ok(image)
 }
 
-/// Creates an ImageTexture from the bytes representing a BMP file.
-/// The image is converted to RGB8 if it is a format that is unsupported in Godot.
-/// The "key color" for cutout transparency is the first color in the BMP palette.  This is stored as metadata in the Godot image to be used later.
-pub fn tex_from_bmp(bmp_data: Vec<u8>) -> Result<Gd<ImageTexture>, &'static str> {
-    let image = image_from_bmp(bmp_data)?;
-    let key_color = image.get_meta("key_color");
+/// Applies the mipmap and compression options to a decoded image.
+pub fn apply_image_options(image: &mut Gd<Image>, options: &TextureOptions) {
+    if options.generate_mipmaps && !image.has_mipmaps() && !image.is_compressed() {
+        // Keyed pixels still have the key color, which would bleed into the edges of the cutout in the smaller mip levels.
+        // Replacing it with the color of the neighbouring opaque pixels keeps the edges clean.
+        if image.detect_alpha() != AlphaMode::NONE {
+            image.fix_alpha_edges();
+        }
+        image.generate_mipmaps();
+    }
+    if options.compress && !image.is_compressed() {
+        let mode = if RenderingServer::singleton().has_os_feature("s3tc") {
+            CompressMode::S3TC
+        } else {
+            CompressMode::ETC2
+        };
+        image.compress(mode);
+    }
+}
+
+/// The "key color" metadata of an image, if it has one.
+/// Only palettized BMPs have a key color, and Godot reports an error when getting metadata that isn't set.
+fn key_color_meta(image: &Gd<Image>) -> Option<Variant> {
+    let name = StringName::from("key_color");
+    image.has_meta(&name).then(|| image.get_meta(&name))
+}
+
+/// Creates an ImageTexture from a Godot Image, carrying over the "key color" metadata if there is any.
+/// The filter hint is stored as "texture_filter" metadata.
+pub fn tex_from_image(image: Gd<Image>, options: &TextureOptions) -> Result<Gd<ImageTexture>, &'static str> {
+    let key_color = key_color_meta(&image);
     let mut tex = ImageTexture::create_from_image(&image)
         .ok_or_else(|| "Failed to create Godot ImageTexture from Godot Image")?;
-    tex.set_meta(&StringName::from("key_color"), &key_color);
+    if let Some(key_color) = key_color {
+        tex.set_meta(&StringName::from("key_color"), &key_color);
+    }
+    tex.set_meta(&StringName::from("texture_filter"), &options.filter.to_variant());
+    Ok(tex)
+}
+
+/// Packs the frames of an animated texture into a Texture2DArray, one layer per frame.
+/// The layers of a Texture2DArray must all match, so if any frame differs from the first frame in size or format,
+/// all frames are converted to uncompressed RGBA8 at the size of the first frame.
+/// The "key color" metadata of the first frame is carried over if it has one, and the frame count and duration are stored as metadata.
+pub fn tex_array_from_images(
+    frames: &[Gd<Image>],
+    frame_duration: f32,
+) -> Result<Gd<Texture2DArray>, &'static str> {
+    let first = frames.first().ok_or("An animated texture needs at least one frame")?;
+    let (width, height) = (first.get_width(), first.get_height());
+    let mipmaps = first.has_mipmaps();
+    let matching = frames.iter().all(|frame| {
+        frame.get_width() == width
+            && frame.get_height() == height
+            && frame.get_format() == first.get_format()
+            && frame.has_mipmaps() == mipmaps
+    });
+    let layers: Array<Gd<Image>> = if matching {
+        frames.iter().cloned().collect()
+    } else {
+        frames
+            .iter()
+            .map(|frame| {
+                // Convert a copy, so that textures already created from the frame are left alone.
+                let mut layer = Image::create_from_data(
+                    frame.get_width(),
+                    frame.get_height(),
+                    frame.has_mipmaps(),
+                    frame.get_format(),
+                    &frame.get_data(),
+                )
+                .unwrap_or_else(|| frame.clone());
+                if layer.is_compressed() {
+                    layer.decompress();
+                }
+                layer.clear_mipmaps();
+                layer.convert(Format::RGBA8);
+                layer.resize(width, height);
+                if mipmaps {
+                    layer.generate_mipmaps();
+                }
+                layer
+            })
+            .collect()
+    };
+    let mut tex = Texture2DArray::new_gd();
+    if tex.create_from_images(&layers) != godot::global::Error::OK {
+        return Err("Failed to create Godot Texture2DArray from frames");
+    }
+    if let Some(key_color) = key_color_meta(first) {
+        tex.set_meta(&StringName::from("key_color"), &key_color);
+    }
+    tex.set_meta(&StringName::from("frame_count"), &(frames.len() as i32).to_variant());
+    tex.set_meta(&StringName::from("frame_duration"), &frame_duration.to_variant());
     Ok(tex)
 }
 
@@ -100,3 +377,22 @@ pub fn tex_from_bmp(bmp_data: Vec<u8>) -> Result<Gd<ImageTexture>, &'static str>
 //     image.load_bmp_from_buffer(PackedByteArray::from(&bmp_data[..]));
 //     Ok(ImageTexture::create_from_image(image).unwrap())
 // }
+
+#[cfg(all(test, feature = "dds"))]
+mod tests {
+    use super::dds_level_sizes;
+
+    #[test]
+    fn dxt1_256x256_full_mip_chain() {
+        let level_sizes = dds_level_sizes(256, 256, 8);
+        assert_eq!(level_sizes.len(), 9);
+        assert_eq!(level_sizes.iter().sum::<usize>(), 43704);
+    }
+
+    #[test]
+    fn dxt5_64x16_full_mip_chain() {
+        let level_sizes = dds_level_sizes(64, 16, 16);
+        assert_eq!(level_sizes, vec![1024, 256, 64, 32, 16, 16, 16]);
+        assert_eq!(level_sizes.iter().sum::<usize>(), 1424);
+    }
+}
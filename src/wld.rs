@@ -1,74 +1,160 @@
+use crate::error::{EQLoadError, LoadError, LoadErrorKind};
 use crate::fragments::{
-    S3DUnknownFragment, S3DActorDef, S3DActorInstance, S3DFragment, S3DHierSprite, S3DMaterial,
-    S3DMesh,
+    ambient_light_color, global_ambient_light_color, region_mesh_indices, RegionType, S3DActorDef,
+    S3DActorInstance, S3DAmbientLight, S3DBlitSprite, S3DDirectionalLight, S3DFragment,
+    S3DGlobalAmbientLight, S3DHierSprite, S3DLight, S3DLightDef, S3DMaterial, S3DMesh,
+    S3DParticleCloud, S3DParticleSprite, S3DPolyhedron, S3DSphere, S3DSphereList, S3DSprite2D,
+    S3DSprite3D, S3DSprite4D, S3DUnknownFragment, S3DZoneRegions,
 };
-use godot::classes::RefCounted;
+use crate::util::collision::CollisionBuilder;
+use godot::classes::{ConcavePolygonShape3D, NavigationMeshSourceGeometryData3D, Resource};
 use godot::obj::bounds::{DeclUser, MemRefCounted};
 use godot::obj::cap::GodotDefault;
 use godot::prelude::*;
 use libeq_wld::parser::{
     Actor, ActorDef, Fragment, FragmentType, HierarchicalSpriteDef,
-    MaterialDef, WldDoc,
+    AmbientLight, BlitSpriteDef, GlobalAmbientLightDef, MaterialDef, ParticleCloudDef, PointLight,
+    Sprite2DDef, WldDoc, WorldTree,
 };
 use std::sync::Arc;
 
 /// Attempts to create a S3D Godot class from the given fragment index - and assert it is of the given type.
-// FIXME: I feel this should return Option - it should fail if the given index is not of the correct type.
+/// Returns an error if the index is out of bounds or the fragment is not of the correct type.
 pub fn gd_from_frag_type<
     T: S3DFragment + GodotDefault<Memory = MemRefCounted, Declarer = DeclUser>,
 >(
     wld: &Arc<WldDoc>,
     index: u32,
-) -> Gd<T> {
+) -> Result<Gd<T>, LoadError> {
     let mut obj = Gd::<T>::default();
-    obj.bind_mut().load(wld, index);
-    obj
+    obj.bind_mut().load(wld, index)?;
+    Ok(obj)
 }
+
 /// Attempts to create a S3D Godot class from the given fragment index, without knowing its type, returning a Variant.
 /// Note that the index supplied is the kind that starts at 1, not 0
+/// Errors are printed in Godot, and nil is returned.
 pub fn gd_from_frag(wld: &Arc<WldDoc>, index: u32) -> Variant {
-    let fragment_type = match wld.at((index - 1) as usize) {
-        Some(myval) => myval,
-        None => {
-            godot_error!("Invalid WLD index: {index}");
-            return Variant::nil();
-        }
-    };
+    try_gd_from_frag(wld, index)
+        .map_err(|e| godot_error!("{e}"))
+        .unwrap_or_default()
+}
+
+/// Attempts to create a S3D Godot class from the given fragment index, without knowing its type.
+/// Note that the index supplied is the kind that starts at 1, not 0
+pub fn try_gd_from_frag(wld: &Arc<WldDoc>, index: u32) -> Result<Variant, LoadError> {
+    let fragment_type = index
+        .checked_sub(1)
+        .and_then(|i| wld.at(i as usize))
+        .ok_or_else(|| {
+            LoadError::new(LoadErrorKind::InvalidFragment, "Invalid WLD index")
+                .with_fragment_index(index)
+        })?;
 
-    match fragment_type {
+    let variant = match fragment_type {
         FragmentType::DmSprite(mesh_reference) => {
             match S3DMesh::from_reference(wld, mesh_reference) {
                 Some(mesh) => Variant::from(mesh),
                 None => Variant::nil(),
             }
         }
-        FragmentType::DmSpriteDef2(_) => Variant::from(gd_from_frag_type::<S3DMesh>(wld, index)),
-        FragmentType::MaterialDef(_) => Variant::from(gd_from_frag_type::<S3DMaterial>(wld, index)),
-        FragmentType::ActorDef(_) => Variant::from(gd_from_frag_type::<S3DActorDef>(wld, index)),
-        FragmentType::Actor(_) => Variant::from(gd_from_frag_type::<S3DActorInstance>(wld, index)),
+        FragmentType::DmSpriteDef2(_) => Variant::from(gd_from_frag_type::<S3DMesh>(wld, index)?),
+        FragmentType::MaterialDef(_) => Variant::from(gd_from_frag_type::<S3DMaterial>(wld, index)?),
+        FragmentType::ActorDef(_) => Variant::from(gd_from_frag_type::<S3DActorDef>(wld, index)?),
+        FragmentType::Actor(_) => Variant::from(gd_from_frag_type::<S3DActorInstance>(wld, index)?),
         FragmentType::HierarchicalSprite(reference) => 
             S3DHierSprite::from_reference(wld, reference).and_then(|frag| Some(Variant::from(frag))).unwrap_or_default(),
         FragmentType::HierarchicalSpriteDef(_) => {
-            Variant::from(gd_from_frag_type::<S3DHierSprite>(wld, index))
+            Variant::from(gd_from_frag_type::<S3DHierSprite>(wld, index)?)
         }
-        _ => Variant::from(gd_from_frag_type::<S3DUnknownFragment>(wld, index)),
-    }
+        FragmentType::LightDef(_) => Variant::from(gd_from_frag_type::<S3DLightDef>(wld, index)?),
+        FragmentType::Light(light) => S3DLightDef::from_reference(wld, light)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::PointLight(_) => Variant::from(gd_from_frag_type::<S3DLight>(wld, index)?),
+        FragmentType::AmbientLight(_) => Variant::from(gd_from_frag_type::<S3DAmbientLight>(wld, index)?),
+        FragmentType::GlobalAmbientLightDef(_) => {
+            Variant::from(gd_from_frag_type::<S3DGlobalAmbientLight>(wld, index)?)
+        }
+        FragmentType::DirectionalLight(_) => {
+            Variant::from(gd_from_frag_type::<S3DDirectionalLight>(wld, index)?)
+        }
+        FragmentType::ParticleCloudDef(_) => Variant::from(gd_from_frag_type::<S3DParticleCloud>(wld, index)?),
+        FragmentType::ParticleSpriteDef(_) => {
+            Variant::from(gd_from_frag_type::<S3DParticleSprite>(wld, index)?)
+        }
+        FragmentType::ParticleSprite(sprite) => S3DParticleSprite::from_reference(wld, sprite)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::BlitSpriteDef(_) => Variant::from(gd_from_frag_type::<S3DBlitSprite>(wld, index)?),
+        FragmentType::BlitSprite(sprite) => S3DBlitSprite::from_reference(wld, sprite)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::Sprite2DDef(_) => Variant::from(gd_from_frag_type::<S3DSprite2D>(wld, index)?),
+        FragmentType::Sprite2D(sprite) => S3DSprite2D::from_reference(wld, sprite)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::Sprite3DDef(_) => Variant::from(gd_from_frag_type::<S3DSprite3D>(wld, index)?),
+        FragmentType::Sprite3D(sprite) => S3DSprite3D::from_reference(wld, sprite)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::Sprite4DDef(_) => Variant::from(gd_from_frag_type::<S3DSprite4D>(wld, index)?),
+        FragmentType::Sprite4D(sprite) => S3DSprite4D::from_reference(wld, sprite)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::PolyhedronDef(_) => Variant::from(gd_from_frag_type::<S3DPolyhedron>(wld, index)?),
+        FragmentType::Polyhedron(polyhedron) => S3DPolyhedron::from_reference(wld, polyhedron)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::SphereListDef(_) => Variant::from(gd_from_frag_type::<S3DSphereList>(wld, index)?),
+        FragmentType::SphereList(sphere_list) => S3DSphereList::from_reference(wld, sphere_list)
+            .map(Variant::from)
+            .unwrap_or_default(),
+        FragmentType::Sphere(_) => Variant::from(gd_from_frag_type::<S3DSphere>(wld, index)?),
+        FragmentType::WorldTree(_) => Variant::from(gd_from_frag_type::<S3DZoneRegions>(wld, index)?),
+        _ => Variant::from(gd_from_frag_type::<S3DUnknownFragment>(wld, index)?),
+    };
+    Ok(variant)
 }
 
+/// A parsed WLD file.  This is a Resource so that it can be returned and cached by `EQResourceFormatLoader`.
 #[derive(GodotClass)]
-#[class(init)]
+#[class(init, base=Resource)]
 pub struct S3DWld {
-    base: Base<RefCounted>,
+    base: Base<Resource>,
     wld: Option<Arc<WldDoc>>,
+    /// The error that caused the most recent call to `at()` to return null.
+    last_error: Option<LoadError>,
+}
+
+/// Parses WLD data without creating any Godot objects, so that it can be done on a worker thread.
+pub fn parse_wld(data: &[u8]) -> Result<Arc<WldDoc>, LoadError> {
+    //fs::write("tmp.wld", &data).expect("Unable to write file");
+    let wld_doc = WldDoc::parse(data).map_err(|err| {
+        LoadError::new(
+            LoadErrorKind::InvalidWld,
+            format!("Failed to parse Wld: {:?}", err),
+        )
+    })?;
+    Ok(Arc::new(wld_doc))
 }
 
+// S3DWld and every fragment wrapper only hold an Arc to the parsed WLD,
+// so a WLD parsed on a worker thread can be wrapped on the main thread.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<WldDoc>();
+};
+
 impl S3DWld {
-    pub fn load(&mut self, data: Vec<u8>) {
-        //fs::write("tmp.wld", &data).expect("Unable to write file");
-        self.wld = match WldDoc::parse(&data[..]) {
-            Ok(wld_doc) => Some(Arc::new(wld_doc)),
-            Err(err) => panic!("Failed to parse Wld: {:?}", err),
-        };
+    pub fn load(&mut self, data: Vec<u8>) -> Result<(), LoadError> {
+        self.load_doc(parse_wld(&data[..])?);
+        Ok(())
+    }
+
+    /// Initializer for a WLD that has already been parsed.
+    pub fn load_doc(&mut self, wld: Arc<WldDoc>) {
+        self.wld = Some(wld);
     }
 
     fn build_fragment_type_array<
@@ -77,21 +163,42 @@ impl S3DWld {
     >(
         &self,
     ) -> Array<Gd<T>> {
-        let wld = self.wld.as_ref().unwrap();
+        let Some(wld) = self.try_get_wld() else {
+            return Array::new();
+        };
         wld.iter()
             .enumerate()
             .filter_map(|(index, fragment)| {
                 let fragment = fragment.as_any().downcast_ref::<T2>();
-                fragment.and_then(|_| Some(gd_from_frag_type::<T>(wld, index as u32 + 1)))
+                fragment.and_then(|_| {
+                    gd_from_frag_type::<T>(wld, index as u32 + 1)
+                        .map_err(|e| godot_error!("{e}"))
+                        .ok()
+                })
             })
             .collect()
     }
 
-    fn get_wld(&self) -> &Arc<WldDoc> {
+    pub fn get_wld(&self) -> &Arc<WldDoc> {
         self.wld
             .as_ref()
             .expect("This class must be initialized with the load() function.")
     }
+
+    /// Like `get_wld`, but prints an error instead of panicking if the WLD has not been loaded.
+    fn try_get_wld(&self) -> Option<&Arc<WldDoc>> {
+        let wld = self.wld.as_ref();
+        if wld.is_none() {
+            godot_error!(
+                "{}",
+                LoadError::new(
+                    LoadErrorKind::InvalidWld,
+                    "This class must be initialized with the load() function.",
+                )
+            );
+        }
+        wld
+    }
 }
 
 #[godot_api]
@@ -100,13 +207,18 @@ impl S3DWld {
     /// This should really only be used for Zone WLDS; for objects, characters etc you should get get_actors
     #[func]
     pub fn meshes(&self) -> Array<Gd<S3DMesh>> {
-        let wld = self.wld.as_ref().unwrap();
+        let Some(wld) = self.try_get_wld() else {
+            return Array::new();
+        };
         wld.iter()
             .enumerate()
             .filter_map(|(index, fragment)| {
                 match fragment.as_ref() {
-                    FragmentType::DmSpriteDef(_) => Some(gd_from_frag_type(wld, index as u32 + 1)),
-                    FragmentType::DmSpriteDef2(_) => Some(gd_from_frag_type(wld, index as u32 + 1)),
+                    FragmentType::DmSpriteDef(_) | FragmentType::DmSpriteDef2(_) => {
+                        gd_from_frag_type(wld, index as u32 + 1)
+                            .map_err(|e| godot_error!("{e}"))
+                            .ok()
+                    }
                     _ => None
 
                 }
@@ -134,14 +246,204 @@ impl S3DWld {
         self.build_fragment_type_array::<S3DHierSprite, HierarchicalSpriteDef>()
     }
 
+    /// Returns an Array of all the point lights in the WLD.
+    /// For zones, these are in the lights.wld - see `EQArchive.get_lights_wld()`.
+    #[func]
+    pub fn lights(&self) -> Array<Gd<S3DLight>> {
+        self.build_fragment_type_array::<S3DLight, PointLight>()
+    }
+
+    /// Returns an Array of all the particle emitters in the WLD.
+    #[func]
+    pub fn particle_clouds(&self) -> Array<Gd<S3DParticleCloud>> {
+        self.build_fragment_type_array::<S3DParticleCloud, ParticleCloudDef>()
+    }
+
+    /// Returns an Array of all the blit sprites in the WLD.
+    #[func]
+    pub fn blit_sprites(&self) -> Array<Gd<S3DBlitSprite>> {
+        self.build_fragment_type_array::<S3DBlitSprite, BlitSpriteDef>()
+    }
+
+    /// Returns an Array of all the 2D sprites in the WLD.
+    #[func]
+    pub fn sprites_2d(&self) -> Array<Gd<S3DSprite2D>> {
+        self.build_fragment_type_array::<S3DSprite2D, Sprite2DDef>()
+    }
+
+    /// Returns the ambient light of the whole zone, or null if the WLD has none.
+    #[func]
+    pub fn global_ambient_light(&self) -> Option<Gd<S3DGlobalAmbientLight>> {
+        self.build_fragment_type_array::<S3DGlobalAmbientLight, GlobalAmbientLightDef>()
+            .iter_shared()
+            .next()
+    }
+
+    /// Returns an Array of the ambient lights that light particular regions of the zone.
+    #[func]
+    pub fn ambient_lights(&self) -> Array<Gd<S3DAmbientLight>> {
+        self.build_fragment_type_array::<S3DAmbientLight, AmbientLight>()
+    }
+
+    /// Returns the ambient color of the given region (see `S3DZoneRegions`).
+    /// This is the color of the ambient light that lists the region, or else the global ambient light, or else black.
+    /// Black is also returned (with an error) if the WLD has not been loaded, so that no light is added.
+    #[func]
+    pub fn region_ambient_color(&self, region: i32) -> Color {
+        let Some(wld) = self.try_get_wld() else {
+            return Color::BLACK;
+        };
+        let region_light = u32::try_from(region).ok().and_then(|region| {
+            wld.iter()
+                .filter_map(|fragment| fragment.as_any().downcast_ref::<AmbientLight>())
+                .find(|light| light.regions.contains(&region))
+        });
+        if let Some(light) = region_light {
+            return ambient_light_color(wld, light);
+        }
+        wld.iter()
+            .find_map(|fragment| fragment.as_any().downcast_ref::<GlobalAmbientLightDef>())
+            .map(global_ambient_light_color)
+            .unwrap_or(Color::BLACK)
+    }
+
+    /// Returns the ambient color for a zone mesh, from the region the mesh belongs to.
+    /// Pass the result to `S3DMesh.lit_vertex_colors()` to get the mesh's vertex colors with the zone's lighting applied.
+    #[func]
+    pub fn mesh_ambient_color(&self, mesh: Gd<S3DMesh>) -> Color {
+        let Some(wld) = self.try_get_wld() else {
+            return Color::BLACK;
+        };
+        let mesh_index = mesh.bind().index();
+        let region = region_mesh_indices(wld)
+            .iter()
+            .position(|&index| index == Some(mesh_index))
+            .map_or(-1, |region| region as i32);
+        self.region_ambient_color(region)
+    }
+
+    /// Returns the regions of a zone (water, lava, zonelines etc.), from the BSP tree in the main WLD of a zone.
+    /// Returns null if the WLD has no BSP tree, or has not been loaded.
+    #[func]
+    pub fn zone_regions(&self) -> Option<Gd<S3DZoneRegions>> {
+        let wld = self.try_get_wld()?;
+        let index = wld
+            .iter()
+            .position(|fragment| fragment.as_any().downcast_ref::<WorldTree>().is_some())?;
+        gd_from_frag_type(wld, index as u32 + 1)
+            .map_err(|e| godot_error!("{e}"))
+            .ok()
+    }
+
+    /// Merges the collision of every mesh in the WLD into ConcavePolygonShape3D resources, in Godot space.
+    /// Passable polygons are left out, as are degenerate and duplicate triangles.
+    /// The shapes are split into square chunks of `chunk_size` Godot units on the XZ plane (by triangle centroid),
+    /// so that the physics engine only has to consider nearby triangles.  A `chunk_size` of 0 returns a single shape.
+    /// This is meant for zone WLDs; placed objects get their collision from their own meshes.
+    #[func]
+    pub fn build_collision(&self, chunk_size: f32) -> Array<Gd<ConcavePolygonShape3D>> {
+        let mut builder = CollisionBuilder::new(chunk_size);
+        for mesh in self.meshes().iter_shared() {
+            let mesh = mesh.bind();
+            let center = mesh.center();
+            let vertices: Vec<Vector3> = mesh
+                .collision_vertices()
+                .as_slice()
+                .iter()
+                .map(|&v| v + center)
+                .collect();
+            builder.add_triangles(&vertices);
+        }
+        builder.build().into_iter().collect()
+    }
+
+    /// Builds the source geometry for baking a NavigationMesh with `NavigationServer3D.bake_from_source_geometry_data()`,
+    /// which does not need the zone to be in the scene tree.
+    /// The geometry is the solid polygons of the zone meshes in this WLD, plus the meshes of the given actor instances.
+    /// `actordefs` maps actordef names to S3DActorDef, since placed objects refer to actordefs in another WLD by name;
+    /// instances that are not in it fall back to `S3DActorInstance.zone_actordef()`.
+    /// If `exclude_water` is true, the meshes of water regions are left out, so that agents path around lakes and rivers.
+    #[func]
+    pub fn navigation_source_geometry(
+        &self,
+        actor_instances: Array<Gd<S3DActorInstance>>,
+        actordefs: Dictionary,
+        exclude_water: bool,
+    ) -> Gd<NavigationMeshSourceGeometryData3D> {
+        let mut geometry = NavigationMeshSourceGeometryData3D::new_gd();
+        // zone_regions() reports the error if the WLD has not been loaded.
+        let water_meshes: Vec<u32> = match (self.zone_regions(), self.wld.as_ref()) {
+            (Some(regions), Some(wld)) if exclude_water => {
+                let regions = regions.bind();
+                region_mesh_indices(wld)
+                    .iter()
+                    .enumerate()
+                    .filter(|(region, _)| {
+                        regions
+                            .get_types(*region as i32)
+                            .iter()
+                            .any(RegionType::is_water)
+                    })
+                    .filter_map(|(_, &mesh_index)| mesh_index)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        for mesh in self.meshes().iter_shared() {
+            let mesh = mesh.bind();
+            if water_meshes.contains(&mesh.index()) {
+                continue;
+            }
+            let transform = Transform3D::new(Basis::IDENTITY, mesh.center());
+            geometry.add_faces(&mesh.collision_vertices(), transform);
+        }
+
+        for actorinst in actor_instances.iter_shared() {
+            let actorinst = actorinst.bind();
+            let actordef = actordefs
+                .get(actorinst.actordef_name())
+                .unwrap_or_else(|| actorinst.zone_actordef());
+            let Ok(actordef) = actordef.try_to::<Gd<S3DActorDef>>() else {
+                continue;
+            };
+            let basis = Basis::from_quat(actorinst.quaternion()) * Basis::from_scale(actorinst.scale());
+            let transform = Transform3D::new(basis, actorinst.position());
+            for mesh in actordef.bind().meshes().iter_shared() {
+                let mesh = mesh.bind();
+                let mesh_transform = transform * Transform3D::new(Basis::IDENTITY, mesh.center());
+                geometry.add_faces(&mesh.collision_vertices(), mesh_transform);
+            }
+        }
+        geometry
+    }
+
     #[func]
     pub fn fragment_count(&self) -> u32 {
         self.get_wld().fragment_count() as u32
     }
 
+    /// Returns the Godot class representation of the fragment at the given index (starting at 1).
+    /// Returns null if the fragment could not be wrapped - see `last_error()` for details.
+    #[func]
+    pub fn at(&mut self, index: u32) -> Variant {
+        let result = try_gd_from_frag(self.get_wld(), index);
+        match result {
+            Ok(variant) => {
+                self.last_error = None;
+                variant
+            }
+            Err(e) => {
+                godot_error!("{e}");
+                self.last_error = Some(e);
+                Variant::nil()
+            }
+        }
+    }
+
+    /// Returns the error that caused the most recent call to `at()` to return null, or null if it succeeded.
     #[func]
-    pub fn at(&self, index: u32) -> Variant {
-        let wld = self.get_wld();
-        gd_from_frag(&wld, index)
+    pub fn last_error(&self) -> Option<Gd<EQLoadError>> {
+        self.last_error.as_ref().map(LoadError::to_gd)
     }
 }